@@ -1,11 +1,20 @@
-use dfu_nusb::core::Dfu;
+use dfu_nusb::core::{CloseMode, Dfu};
 use dfu_nusb::error::Error;
-use dfu_nusb::status::State;
+use dfu_nusb::option_bytes::{Family, OptionBytes};
+use dfu_nusb::progress::{Phase, Progress, ProgressObserver};
+use dfu_nusb::status::{PollLimits, State};
+use dfu_nusb::trace::Recorder;
+use dfu_nusb::transport::{
+    find_dfu_interface, DfuTransport, NusbTransport, DFU_PROTOCOL_DFU, DFU_PROTOCOL_RUNTIME,
+};
+use indicatif::{ProgressBar, ProgressStyle};
 use log::info;
 use pretty_hex::PrettyHex;
 use std::fmt;
 use std::fs::{File, OpenOptions};
-use std::path::PathBuf;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use structopt::StructOpt;
 
 fn parse_int(src: &str) -> Result<u32, std::num::ParseIntError> {
@@ -101,7 +110,7 @@ struct VWFlashArgs {
     /// start address[:length]
     #[structopt(short = "s", long, default_value = "0x08000000", parse(try_from_str=parse_address_and_length_as_some))]
     address: (u32, Option<u32>),
-    /// Read firmware into <file>
+    /// Firmware <file>, '-' for stdin with an explicit length
     #[structopt(short = "f", long)]
     file_name: PathBuf,
 }
@@ -111,13 +120,53 @@ struct ReadFlashArgs {
     /// start address[:length]
     #[structopt(short = "s", long, default_value = "0x08000000", parse(try_from_str=parse_address_and_length))]
     address: (u32, u32),
-    /// Read firmware into <file>
+    /// Read firmware into <file>, '-' for stdout
     #[structopt(short = "f", long)]
     file_name: PathBuf,
     #[structopt(short = "F", long)]
     overwrite: bool,
 }
 
+#[derive(StructOpt, PartialEq)]
+enum OtpAction {
+    /// Show the OTP blocks and whether they are locked
+    Show,
+    /// Program a file into OTP, refusing to change programmed bytes
+    Write {
+        /// start address
+        #[structopt(short = "s", long, parse(try_from_str=parse_int))]
+        address: u32,
+        /// Program <file>, '-' for stdin
+        #[structopt(short = "f", long)]
+        file_name: PathBuf,
+    },
+}
+
+#[derive(StructOpt, PartialEq)]
+struct ReadUnprotectArgs {
+    /// Don't ask before mass erasing the device
+    #[structopt(short, long)]
+    yes: bool,
+}
+
+#[derive(StructOpt, PartialEq)]
+enum OptionBytesAction {
+    /// Decode the option bytes
+    Show {
+        /// Part of a 0x1FFFF800 block, f0, f04x, f09x, f1 or f3, to decode nBOOT0/nBOOT1
+        #[structopt(long)]
+        family: Option<Family>,
+    },
+    /// Change option bytes, e.g. bor=off nrst_stop=0 wrp=0x0F, bor takes off, 1, 2 or 3
+    Set {
+        /// Part of a 0x1FFFF800 block, f0, f04x, f09x, f1 or f3, to change nBOOT0/nBOOT1
+        #[structopt(long)]
+        family: Option<Family>,
+        #[structopt(required = true)]
+        changes: Vec<String>,
+    },
+}
+
 #[derive(StructOpt, PartialEq)]
 enum Action {
     SupportedCommands,
@@ -131,6 +180,14 @@ enum Action {
     SetAddress(STMResetArgs),
     MemoryLayout,
     ReadAddress(AddressArgs),
+    /// List the alt settings of the DFU interface
+    AltSettings,
+    /// STM32 option bytes from the "Option Bytes" alt setting
+    OptionBytes(OptionBytesAction),
+    /// One-time programmable area from the "OTP Memory" alt setting
+    Otp(OtpAction),
+    /// Remove readout protection, mass erasing the flash
+    ReadUnprotect(ReadUnprotectArgs),
 }
 
 impl fmt::Display for Action {
@@ -163,6 +220,16 @@ impl fmt::Display for Action {
             SetAddress(a) => write!(f, "Set address 0x{:08X}", a.address),
             Detach => write!(f, "Detach"),
             MemoryLayout => write!(f, "Memory layout"),
+            AltSettings => write!(f, "Alt settings"),
+            OptionBytes(OptionBytesAction::Show { .. }) => write!(f, "Show option bytes"),
+            OptionBytes(OptionBytesAction::Set { changes, .. }) => {
+                write!(f, "Set option bytes {}", changes.join(" "))
+            }
+            Otp(OtpAction::Show) => write!(f, "Show OTP"),
+            Otp(OtpAction::Write { address, file_name }) => {
+                write!(f, "Write file: '{:?}' to OTP at address: 0x{:08X}", file_name, address)
+            }
+            ReadUnprotect(_) => write!(f, "Read unprotect"),
             ReadAddress(a) => write!(f, "Read address 0x{:08X} length: {} bytes", a.address.0, a.address.1),
         }
     }
@@ -185,6 +252,12 @@ struct Args {
     /// Specify Alt setting of the DFU interface by number
     #[structopt(short, long, default_value = "0")]
     alt: u8,
+    /// Specify Alt setting of the DFU interface by name, e.g. "Option Bytes"
+    #[structopt(short = "n", long)]
+    alt_name: Option<String>,
+    /// Device runs its application, detach it into DFU mode first
+    #[structopt(short = "R", long)]
+    runtime: bool,
     #[structopt(skip)]
     bus: u8,
     #[structopt(skip)]
@@ -193,6 +266,18 @@ struct Args {
     action: Action,
     #[structopt(short, long, parse(from_occurrences))]
     verbose: usize,
+    /// Record all USB control traffic to <file> for replay
+    #[structopt(long)]
+    record: Option<PathBuf>,
+    /// Shortest wait in ms between status requests
+    #[structopt(long, default_value = "1")]
+    poll_min: u64,
+    /// Longest wait in ms between status requests, whatever the device asks for
+    #[structopt(long)]
+    poll_max: Option<u64>,
+    /// Seconds to wait for the device to come back after read-unprotect
+    #[structopt(long, default_value = "60")]
+    reconnect_timeout: u64,
 }
 
 impl Args {
@@ -221,17 +306,26 @@ impl Args {
             let e = nusb::list_devices()?;
             let mut msg =
                 String::from("Missing --bus-device or --dev! List of possible USB devices:\n\n");
-            for (bus, dev) in e.filter(|dev| dev.product_id() == 0xdf11).map(|dev| {
-                (
-                    format!("{:04X}:{:04X}", dev.bus_number(), dev.device_address()),
-                    dev,
-                )
-            }) {
+            for (bus, dev) in e
+                .filter(|dev| {
+                    dev.product_id() == 0xdf11
+                        || find_dfu_interface(dev, DFU_PROTOCOL_DFU).is_some()
+                        || find_dfu_interface(dev, DFU_PROTOCOL_RUNTIME).is_some()
+                })
+                .map(|dev| {
+                    (
+                        format!("{:04X}:{:04X}", dev.bus_number(), dev.device_address()),
+                        dev,
+                    )
+                })
+            {
+                let runtime = find_dfu_interface(&dev, DFU_PROTOCOL_RUNTIME).is_some();
                 msg += &format!(
-                    "--bus-device {} or -d {:04X}:{:04X}\n",
+                    "--bus-device {} or -d {:04X}:{:04X}{}\n",
                     bus,
                     dev.vendor_id(),
                     dev.product_id(),
+                    if runtime { " with --runtime" } else { "" },
                 );
             }
             return Err(Error::Argument(msg));
@@ -241,37 +335,107 @@ impl Args {
     }
 }
 
-fn get_length_from_file(file: &File, length: Option<u32>) -> Result<u32, Error> {
-    let file_length = file.metadata()?.len() as u32;
-    Ok(match length {
-        Some(length) => {
-            if file_length < length {
-                return Err(Error::Argument(format!(
-                    "error on '{:?}' is {} bytes, but length is set to {} bytes",
-                    file, file_length, length
-                )));
-            }
-            length
-        }
-        None => {
-            if file_length == 0 {
-                return Err(Error::Argument(format!("File '{:?}' is empty", file)));
-            }
-            file_length
-        }
-    })
+/// Open <file> for reading, '-' reads stdin.
+fn input(path: &Path) -> Result<Box<dyn Read>, Error> {
+    if path == Path::new("-") {
+        return Ok(Box::new(std::io::stdin().lock()));
+    }
+    Ok(Box::new(BufReader::new(File::open(path)?)))
+}
+
+/// Length to take from stdin when <file> is '-', which can't be measured
+/// up front.
+fn stdin_length(a: &VWFlashArgs) -> Result<Option<u32>, Error> {
+    if a.file_name != Path::new("-") {
+        return Ok(None);
+    }
+    a.address
+        .1
+        .map(Some)
+        .ok_or_else(|| Error::Argument("Reading stdin needs a length, use -s address:length".into()))
+}
+
+/// Open <file> for writing, '-' writes stdout.
+fn output(path: &Path, overwrite: bool) -> Result<Box<dyn Write>, Error> {
+    if path == Path::new("-") {
+        return Ok(Box::new(std::io::stdout().lock()));
+    }
+    let file = OpenOptions::new()
+        .write(true)
+        .create(overwrite)
+        .truncate(overwrite)
+        .create_new(!overwrite)
+        .open(path)?;
+    Ok(Box::new(BufWriter::new(file)))
 }
 
 async fn run_main() -> Result<(), Error> {
     let args = Args::new()?;
-    let mut dfu = if args.id_vendor != 0 && args.id_product != 0 {
-        Dfu::from_vid_pid(args.id_vendor, args.id_product, args.intf, args.alt).await?
+    let mut transport = if args.runtime {
+        if args.id_vendor != 0 && args.id_product != 0 {
+            NusbTransport::detach_from_vid_pid(args.id_vendor, args.id_product).await?
+        } else {
+            NusbTransport::detach_from_bus_device(args.bus, args.device).await?
+        }
+    } else if args.id_vendor != 0 && args.id_product != 0 {
+        NusbTransport::from_vid_pid(args.id_vendor, args.id_product, args.intf)?
     } else {
-        Dfu::from_bus_device(args.bus, args.device, args.intf, args.alt).await?
+        NusbTransport::from_bus_device(args.bus, args.device, args.intf)?
     };
-    dfu.status_wait_for(0, Some(State::DfuIdle)).await?;
-    log::info!("Execute action: {}", args.action);
-    match args.action {
+    transport.set_reconnect_timeout(Duration::from_secs(args.reconnect_timeout));
+    let poll_limits = PollLimits {
+        min: Duration::from_millis(args.poll_min),
+        max: args.poll_max.map(Duration::from_millis),
+        ..PollLimits::default()
+    };
+    if let Some(record) = &args.record {
+        let recorder = Recorder::new(transport, File::create(record)?);
+        let dfu = open(recorder, &args).await?;
+        run_action(dfu, poll_limits, args.action).await
+    } else {
+        let dfu = open(transport, &args).await?;
+        run_action(dfu, poll_limits, args.action).await
+    }
+}
+
+async fn open<T: DfuTransport>(transport: T, args: &Args) -> Result<Dfu<T>, Error> {
+    match &args.alt_name {
+        Some(name) => Dfu::from_transport_alt_name(transport, name).await,
+        None => Dfu::from_transport(transport, args.alt).await,
+    }
+}
+
+async fn run_action<T: DfuTransport>(
+    mut dfu: Dfu<T>,
+    poll_limits: PollLimits,
+    action: Action,
+) -> Result<(), Error> {
+    dfu.set_poll_limits(poll_limits);
+    dfu.set_progress(ProgressReport::default());
+    let cancel = dfu.cancel_token();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            log::warn!("Stopping after the current block, press Ctrl-C again to quit at once");
+            cancel.cancel();
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(130);
+            }
+        }
+    });
+    // a detach also resets the device when it won't leave DFU by itself
+    let mode = if let Action::Detach = action { CloseMode::Detach } else { CloseMode::StayInDfu };
+    let res = execute(&mut dfu, action).await;
+    let closed = dfu.close(mode).await;
+    if let (Err(_), Err(e)) = (&res, &closed) {
+        log::warn!("Close failed {}", e);
+    }
+    res.and(closed)
+}
+
+async fn execute<T: DfuTransport>(dfu: &mut Dfu<T>, action: Action) -> Result<(), Error> {
+    dfu.status_wait_for(Duration::ZERO, Some(State::DfuIdle)).await?;
+    log::info!("Execute action: {}", action);
+    match action {
         Action::SupportedCommands => {
             let supported_cmds = dfu.dfuse_get_commands().await?;
             println!("Supported commands:");
@@ -281,31 +445,34 @@ async fn run_main() -> Result<(), Error> {
             Ok(())
         }
         Action::Reset(a) => dfu.reset_stm32(a.address).await,
-        Action::Read(a) => dfu.upload(
-            &mut OpenOptions::new()
-                .write(true)
-                .create(a.overwrite)
-                .truncate(a.overwrite)
-                .create_new(!a.overwrite)
-                .open(a.file_name)?,
-            a.address.0,
-            a.address.1,
-        ).await,
-        Action::Write(a) => {
-            let f = &mut OpenOptions::new().read(true).open(a.file_name)?;
-            let len = get_length_from_file(f, a.address.1).unwrap();
-            dfu.download_raw(f, a.address.0, len).await
+        Action::Read(a) => {
+            let mut out = output(&a.file_name, a.overwrite)?;
+            dfu.upload(&mut out, a.address.0, a.address.1).await?;
+            out.flush()?;
+            Ok(())
         }
+        Action::Write(a) => match stdin_length(&a)? {
+            Some(length) => dfu.download_stream(&mut std::io::stdin().lock(), a.address.0, length).await,
+            None => {
+                let mut file = BufReader::new(File::open(&a.file_name)?);
+                dfu.download_raw(&mut file, a.address.0, a.address.1).await
+            }
+        },
         Action::Verify(a) => {
-            let f = &mut OpenOptions::new().read(true).open(a.file_name)?;
-            let len = get_length_from_file(f, a.address.1).unwrap();
-            dfu.verify(f, a.address.0, len).await?;
+            match stdin_length(&a)? {
+                Some(length) => dfu.verify_stream(&mut std::io::stdin().lock(), a.address.0, length).await?,
+                None => {
+                    let mut file = BufReader::new(File::open(&a.file_name)?);
+                    dfu.verify(&mut file, a.address.0, a.address.1).await?
+                }
+            }
             info!("Verify done");
             Ok(())
         }
         Action::EraseAll => dfu.mass_erase().await,
         Action::Erase(a) => dfu.erase_pages(a.address.0, a.address.1).await,
-        Action::Detach => dfu.detach().await,
+        // done when closing
+        Action::Detach => Ok(()),
         Action::ReadAddress(a) => {
             let mut buf = vec![0; a.address.1 as usize];
             let len = dfu.read_flash_to_slice(a.address.0, &mut buf).await?;
@@ -317,14 +484,118 @@ async fn run_main() -> Result<(), Error> {
         }
         Action::SetAddress(a) => dfu.set_address(a.address).await,
         Action::MemoryLayout => {
-            dfu.memory_layout().pages().iter().for_each(|p| {
-                println!("Start: 0x{:08X} Size: {} bytes", p.address, p.size)
-            });
+            println!("{}", dfu.memory_layout());
+            Ok(())
+        }
+        Action::AltSettings => {
+            println!("{}", dfu.device());
+            Ok(())
+        }
+        Action::OptionBytes(OptionBytesAction::Show { family }) => {
+            println!("{}", read_option_bytes(dfu, family).await?);
             Ok(())
         }
+        Action::OptionBytes(OptionBytesAction::Set { family, changes }) => {
+            let mut options = read_option_bytes(dfu, family).await?;
+            for change in changes {
+                let (key, value) = change
+                    .split_once('=')
+                    .ok_or_else(|| Error::Argument(format!("Expect key=value, got {}", change)))?;
+                options.set(key, value)?;
+            }
+            dfu.write_option_bytes(&options).await?;
+            println!("{}", read_option_bytes(dfu, family).await?);
+            Ok(())
+        }
+        Action::Otp(OtpAction::Show) => {
+            print!("{}", dfu.read_otp().await?);
+            Ok(())
+        }
+        Action::Otp(OtpAction::Write { address, file_name }) => {
+            let mut buf = Vec::new();
+            input(&file_name)?.read_to_end(&mut buf)?;
+            dfu.write_otp(address, &buf).await?;
+            print!("{}", dfu.read_otp().await?);
+            Ok(())
+        }
+        Action::ReadUnprotect(a) => {
+            if !a.yes && !confirm("Read unprotect mass erases the whole flash, type 'yes' to continue: ")? {
+                return Err(Error::Argument("Read unprotect not confirmed".into()));
+            }
+            dfu.read_unprotect().await?;
+            info!("Readout protection removed");
+            Ok(())
+        }
+    }
+}
+
+async fn read_option_bytes<T: DfuTransport>(
+    dfu: &mut Dfu<T>,
+    family: Option<Family>,
+) -> Result<OptionBytes, Error> {
+    let options = dfu.read_option_bytes().await?;
+    match family {
+        Some(family) => options.with_family(family),
+        None => Ok(options),
     }
 }
 
+/// Progress bar per phase, with throughput and ETA, drawn on stderr.
+#[derive(Default)]
+struct ProgressReport {
+    bar: Option<ProgressBar>,
+}
+
+impl ProgressObserver for ProgressReport {
+    fn progress(&mut self, event: &Progress) {
+        match event {
+            Progress::Start { phase, address, total } => {
+                let bar = if *total == 0 {
+                    let bar = ProgressBar::new_spinner()
+                        .with_style(ProgressStyle::with_template("{prefix:8} {spinner} {bytes} {msg}").unwrap());
+                    bar.enable_steady_tick(Duration::from_millis(100));
+                    bar
+                } else {
+                    ProgressBar::new(*total as u64).with_style(
+                        ProgressStyle::with_template(
+                            "{prefix:8} [{bar:40}] {bytes}/{total_bytes} {binary_bytes_per_sec} ETA {eta} {msg}",
+                        )
+                        .unwrap()
+                        .progress_chars("=> "),
+                    )
+                };
+                bar.set_prefix(phase.to_string());
+                if *phase != Phase::Manifest {
+                    bar.set_message(format!("0x{:08X}", address));
+                }
+                self.bar = Some(bar);
+            }
+            Progress::Bytes { address, done, .. } => {
+                if let Some(bar) = &self.bar {
+                    bar.set_position(*done as u64);
+                    bar.set_message(format!("0x{:08X}", address));
+                }
+            }
+            Progress::SectorErased(sector) => {
+                log::debug!("Erased 0x{:08X} {} bytes", sector.address, sector.size);
+            }
+            Progress::End { .. } => {
+                if let Some(bar) = self.bar.take() {
+                    bar.finish_with_message("done");
+                }
+            }
+        }
+    }
+}
+
+fn confirm(question: &str) -> Result<bool, Error> {
+    print!("{}", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim() == "yes")
+}
+
 fn env_logger_init(_appname: &str, verbose: usize) {
     use env_logger::Builder;
     use log::LevelFilter;
@@ -1,32 +1,34 @@
+use crate::cancel::CancelToken;
+use crate::descriptor::DfuDescriptor;
+use crate::device::{AltSelector, DfuDevice};
 use crate::dfuse_command::DfuseCommand;
 use crate::error::Error;
-use crate::memory_layout::MemoryLayout;
-use crate::status::{State, Status};
+use crate::memory_layout::{Access, EraseSector, MemoryLayout};
+use crate::option_bytes::{RdpLevel, OPTION_BYTES_ALT};
+use crate::progress::{Phase, Progress, ProgressObserver, Tracker};
+use crate::status::{PollLimits, State, Status, StatusCode};
+use crate::transport::{is_stall, DfuTransport, NusbTransport};
+use crate::timer::{self, Instant};
 use std::convert::TryFrom;
-use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::str::FromStr;
 use std::time::Duration;
-use futures_lite::future::block_on;
-use nusb;
-use nusb::descriptors::language_id::US_ENGLISH;
-use nusb::descriptors::Descriptor;
-use nusb::transfer::{ControlIn, ControlOut, ControlType, Recipient};
-#[allow(dead_code)]
-const DFU_DETACH: u8 = 0;
-const DFU_DNLOAD: u8 = 1;
-#[allow(dead_code)]
-const DFU_UPLOAD: u8 = 2;
+pub(crate) const DFU_DETACH: u8 = 0;
+pub(crate) const DFU_DNLOAD: u8 = 1;
+pub(crate) const DFU_UPLOAD: u8 = 2;
 pub(crate) const DFU_GET_STATUS: u8 = 3;
-const DFU_CLRSTATUS: u8 = 4;
+pub(crate) const DFU_CLRSTATUS: u8 = 4;
 #[allow(dead_code)]
-const DFU_GETSTATE: u8 = 5;
-#[allow(dead_code)]
-const DFU_ABORT: u8 = 6;
+pub(crate) const DFU_GETSTATE: u8 = 5;
+pub(crate) const DFU_ABORT: u8 = 6;
 
+/// DfuSe data blocks from 2 on, each at `base + (transaction - 2) * xfer_max`.
+/// Before the block number runs out the numbering restarts at 2 from the
+/// current address, which must then be set on the device again.
 #[derive(Debug)]
 struct Transaction {
     transaction: u16,
+    base: u32,
     address: u32,
     pending: u32,
     xfer: u16,
@@ -37,6 +39,7 @@ impl Transaction {
     fn new(address: u32, pending: u32, xfer_max: u16) -> Self {
         let mut t = Transaction {
             transaction: 2,
+            base: address,
             address,
             pending,
             xfer: xfer_max,
@@ -64,206 +67,348 @@ impl Iterator for Transaction {
             self.xfer = 0;
             return None;
         }
-        self.set_xfer();
         self.address += self.xfer as u32;
-        self.transaction += 1;
+        self.set_xfer();
+        if self.transaction == u16::MAX {
+            self.transaction = 2;
+            self.base = self.address;
+        } else {
+            self.transaction += 1;
+        }
         Some(())
     }
 }
 
-pub struct DfuDescriptor {
-    pub attributes: u8,
-    pub detach_timeout: u16,
-    pub transfer_size: u16,
-    pub dfu_version: u8,
+/// Protocol spoken by the device, picked from bcdDFUVersion.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    /// Plain DFU 1.1: blocks from 0, no address pointer, upload ends on a
+    /// short packet and download ends with manifestation.
+    Dfu,
+    /// ST DfuSe: commands on block 0, data from block 2 at the address set
+    /// with `DfuseCommand::SetAddress`.
+    Dfuse,
 }
 
-impl DfuDescriptor {
-    fn new(desc: Descriptor) -> Option<Self> {
-        let mut iter = desc.iter();
-        // length
-        if *iter.next()? != 9 {
-            return None;
-        }
-
-        // type
-        if *iter.next()? != 33 {
-            return None;
-        }
+/// How the device came out of the manifestation phase.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Manifestation {
+    /// Manifestation tolerant device, back in dfuIDLE and ready for more
+    /// requests.
+    StayedInDfu,
+    /// Device left DFU mode, either after the USB reset issued for
+    /// dfuMANIFEST-WAIT-RESET or by detaching itself.
+    Reset,
+}
 
-        Some(DfuDescriptor {
-            attributes: *iter.next()?,
-            detach_timeout: *iter.next()? as u16 | (*iter.next()? as u16) << 8,
-            transfer_size: *iter.next()? as u16 | (*iter.next()? as u16) << 8,
-            dfu_version: *iter.next()?,
-        })
-    }
+/// How `Dfu::close` leaves the device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloseMode {
+    /// Abort to dfuIDLE and stay in DFU mode.
+    StayInDfu,
+    /// Leave DFU mode and run the firmware. DfuSe devices jump to the
+    /// address through manifestation, plain DFU devices get a USB reset.
+    Leave(u32),
+    /// Send DFU_DETACH, followed by a USB reset unless the device detaches
+    /// itself.
+    Detach,
+    /// USB reset.
+    Reset,
 }
 
-pub struct Dfu {
-    usb: nusb::Device,
-    interface: nusb::Interface,
+pub struct Dfu<T: DfuTransport = NusbTransport> {
+    transport: T,
     detached: bool,
-    dfu_descriptor: DfuDescriptor,
+    closed: bool,
+    device: DfuDevice,
+    alt: u8,
+    protocol: Protocol,
     mem_layout: MemoryLayout,
+    poll_limits: PollLimits,
+    next_poll: Option<Instant>,
+    next_block: u16,
+    progress: Option<Box<dyn ProgressObserver>>,
+    cancel: CancelToken,
 }
 
-impl Drop for Dfu {
+impl<T: DfuTransport> Drop for Dfu<T> {
     fn drop(&mut self) {
-        if self.detached {
-            return;
+        // nothing can be awaited here, so the device is left as it is
+        if !self.closed && !self.detached {
+            log::warn!("Dfu dropped without close, the device is left in its current state");
         }
-        if block_on(self.status_wait_for(0, Some(State::DfuIdle))).is_err() {
-            log::debug!("Dfu was not idle abort to idle");
-            block_on(self.abort_to_idle()).unwrap_or_else(|e| {
-                log::warn!("Abort to idle failed {}", e);
-            });
-        }
-        // self.usb
-        //     .release_interface(self.interface as u32)
-        //     .unwrap_or_else(|e| {
-        //         log::warn!("Release interface failed with {}", e);
-        //     });
     }
 }
 
-impl Dfu {
-    fn setup(usb: nusb::Device, iface_index: u8, alt_index: u8) -> Result<Self, Error> {
-        let interface = usb.claim_interface(iface_index).map_err(|e| {
-            log::error!("Claim interface failed with {}", e);
-            Error::USB("Claim interface failed".into(), e)
-        })?;
-
-        let conf = usb.active_configuration().map_err(|_| {
-            Error::DeviceNotFound("Missing active configuration".to_string())
-        })?;
-
-        let alt = conf.interface_alt_settings().find(|s| {
-            s.interface_number() == iface_index && s.alternate_setting() == alt_index
-        }).ok_or_else(|| {
-            Error::DeviceNotFound("Missing configuration alt setting".to_string())
-        })?;
-
-        let mem_layout = MemoryLayout::from_str(
-            &alt.string_index().map(|i| usb.get_string_descriptor(i, US_ENGLISH, Duration::from_secs(1)).unwrap()).ok_or_else(|| {
-                Error::DeviceNotFound("Missing configuration descriptor".to_string())
-            })?
-        )?;
-        
-        let dfu_descriptor = conf.descriptors()
-        .find(|desc| desc.descriptor_type() == 33)
-        .map(|desc| DfuDescriptor::new(desc.clone())).ok_or_else(|| {
-            Error::DeviceNotFound("Missing configuration dfu transfer descriptor".to_string())
-        })?.unwrap();
-
-        interface.set_alt_setting(alt_index).unwrap();
-
-        log::debug!("Transfer size: {} bytes", dfu_descriptor.transfer_size);
+impl Dfu<NusbTransport> {
+    pub async fn from_bus_device(bus: u8, dev_addr: u8, iface_index: u8, alt: u8) -> Result<Self, Error> {
+        let transport = NusbTransport::from_bus_device(bus, dev_addr, iface_index)?;
+        Dfu::from_transport(transport, alt).await
+    }
+
+    pub async fn from_vid_pid(vid: u16, pid: u16, iface_index: u8, alt: u8) -> Result<Self, Error> {
+        let transport = NusbTransport::from_vid_pid(vid, pid, iface_index)?;
+        Dfu::from_transport(transport, alt).await
+    }
+
+    /// Open a device running its application, detaching it into DFU mode
+    /// first. A device already in DFU mode is opened directly.
+    pub async fn from_runtime_vid_pid(vid: u16, pid: u16, alt: u8) -> Result<Self, Error> {
+        let transport = NusbTransport::detach_from_vid_pid(vid, pid).await?;
+        Dfu::from_transport(transport, alt).await
+    }
+
+    pub async fn from_runtime_bus_device(bus: u8, dev_addr: u8, alt: u8) -> Result<Self, Error> {
+        let transport = NusbTransport::detach_from_bus_device(bus, dev_addr).await?;
+        Dfu::from_transport(transport, alt).await
+    }
+
+    pub fn usb(&mut self) -> &mut nusb::Device {
+        self.transport.usb()
+    }
+}
+
+impl<T: DfuTransport> Dfu<T> {
+    fn setup(mut transport: T, device: DfuDevice, alt: u8) -> Result<Self, Error> {
+        let protocol = device.descriptor.protocol();
+        let mem_layout = Self::alt_layout(&device, alt)?;
+
+        transport.set_alt_setting(alt).map_err(|e| Error::USB("Set alt setting".into(), e))?;
+
+        log::debug!("Transfer size: {} bytes", device.descriptor.transfer_size);
+        log::debug!("DFU version: {} {:?}", device.descriptor.dfu_version, protocol);
         Ok(Self {
-            usb,
-            interface,
-            dfu_descriptor,
+            transport,
+            device,
+            alt,
             detached: false,
+            closed: false,
+            protocol,
             mem_layout,
+            poll_limits: PollLimits::default(),
+            next_poll: None,
+            next_block: 0,
+            progress: None,
+            cancel: CancelToken::new(),
         })
     }
 
-    pub async fn from_bus_device(bus: u8, dev_addr: u8, iface_index: u8, alt: u8) -> Result<Self, Error> {
-        
-        let device = nusb::list_devices()
-        .unwrap()
-        .find(|dev| dev.bus_number() == bus && dev.device_address() == dev_addr)
-        .expect("device not connected");
+    /// Memory layout of `alt`, DfuSe requires one.
+    fn alt_layout(device: &DfuDevice, alt: u8) -> Result<MemoryLayout, Error> {
+        let alt_setting = device.alt(alt);
+        match (device.descriptor.protocol(), alt_setting) {
+            (Protocol::Dfuse, None) => {
+                Err(Error::DeviceNotFound("Missing configuration alt setting".to_string()))
+            }
+            // parse again for the error of a broken layout
+            (Protocol::Dfuse, Some(a)) => match &a.layout {
+                Some(layout) => Ok(layout.clone()),
+                None => MemoryLayout::from_str(&a.string),
+            },
+            // plain DFU names the alt setting freely, there is no layout to parse
+            (Protocol::Dfu, _) => Ok(MemoryLayout::default()),
+        }
+    }
 
-        let usb = device.open().map_err(|e| Error::USB("open".into(), e))?;
+    /// Functional descriptor and alt settings of the interface.
+    pub fn device(&self) -> &DfuDevice {
+        &self.device
+    }
 
-        let mut dfu = Dfu::setup(usb, iface_index, alt)?;
-        dfu.abort_to_idle_clear_once().await?;
-        Ok(dfu)
+    /// Alt setting in use.
+    pub fn alt(&self) -> u8 {
+        self.alt
     }
 
-    pub async fn from_vid_pid(vid: u16, pid: u16, iface_index: u8, alt: u8) -> Result<Self, Error> {
-        
-        let device = nusb::list_devices()
-        .unwrap()
-        .find(|dev| dev.vendor_id() == vid && dev.product_id() == pid)
-        .expect("device not connected");
+    /// Switch to another alt setting, by number or name, without reopening
+    /// the device. The device is brought back to dfuIDLE first and the memory
+    /// layout of the new alt setting replaces the current one.
+    pub async fn select_alt<A: Into<AltSelector>>(&mut self, alt: A) -> Result<(), Error> {
+        let alt = self.device.resolve(&alt.into())?;
+        let mem_layout = Self::alt_layout(&self.device, alt)?;
+        self.abort_to_idle_clear_once().await?;
+        self.transport.set_alt_setting(alt).map_err(|e| Error::USB("Set alt setting".into(), e))?;
+        log::debug!("Selected alt setting {}", alt);
+        self.alt = alt;
+        self.mem_layout = mem_layout;
+        self.next_block = 0;
+        Ok(())
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
 
-        let usb = device.open().map_err(|e| Error::USB("open".into(), e))?;
+    pub fn poll_limits(&self) -> &PollLimits {
+        &self.poll_limits
+    }
 
-        let mut dfu = Dfu::setup(usb, iface_index, alt)?;
+    /// Set the floor and cap applied to the device's bwPollTimeout.
+    pub fn set_poll_limits(&mut self, poll_limits: PollLimits) {
+        self.poll_limits = poll_limits;
+    }
+
+    /// Report erase, write, read, verify and manifestation progress to
+    /// `observer`, e.g. a closure taking `&Progress`.
+    pub fn set_progress<P: ProgressObserver + 'static>(&mut self, observer: P) {
+        self.progress = Some(Box::new(observer));
+    }
+
+    pub fn clear_progress(&mut self) {
+        self.progress = None;
+    }
+
+    /// End the session, leaving the device as `mode` says.
+    /// Nothing is sent if the device already left DFU mode, e.g. after
+    /// manifestation.
+    pub async fn close(mut self, mode: CloseMode) -> Result<(), Error> {
+        self.closed = true;
+        if self.detached {
+            return Ok(());
+        }
+        match mode {
+            CloseMode::StayInDfu => self.abort_to_idle_clear_once().await,
+            CloseMode::Leave(address) => {
+                self.abort_to_idle_clear_once().await?;
+                match self.protocol {
+                    Protocol::Dfuse => self.reset_stm32(address).await,
+                    Protocol::Dfu => self.transport.reset().map_err(|e| Error::USB("Reset".into(), e)),
+                }
+            }
+            CloseMode::Detach => {
+                self.detach().await?;
+                if self.device.descriptor.will_detach {
+                    return Ok(());
+                }
+                self.transport.reset().map_err(|e| Error::USB("Reset".into(), e))
+            }
+            CloseMode::Reset => self.transport.reset().map_err(|e| Error::USB("Reset".into(), e)),
+        }
+    }
+
+    /// Token that stops the running erase, write, read or verify between
+    /// two blocks.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Abort to dfuIDLE and fail with `Error::Cancelled` if the token was
+    /// cancelled, `address` being where the operation got to.
+    async fn check_cancel(&mut self, address: u32) -> Result<(), Error> {
+        if !self.cancel.is_cancelled() {
+            return Ok(());
+        }
+        log::info!("Cancelled at 0x{:08X}", address);
+        self.abort_to_idle_clear_once().await?;
+        Err(Error::Cancelled(address))
+    }
+
+    pub fn dfu_descriptor(&self) -> &DfuDescriptor {
+        &self.device.descriptor
+    }
+
+    fn require_dfuse(&self, op: &str) -> Result<(), Error> {
+        if self.protocol != Protocol::Dfuse {
+            return Err(Error::Unsupported(format!("{} needs a DfuSe device", op)));
+        }
+        Ok(())
+    }
+
+    fn require_download(&self, op: &str) -> Result<(), Error> {
+        if !self.device.descriptor.can_download {
+            return Err(Error::Unsupported(format!("{} on a device without download support", op)));
+        }
+        Ok(())
+    }
+
+    fn require_upload(&self, op: &str) -> Result<(), Error> {
+        if !self.device.descriptor.can_upload {
+            return Err(Error::Unsupported(format!("{} on a device without upload support", op)));
+        }
+        Ok(())
+    }
+
+    /// Open alt setting `alt` on an already claimed transport.
+    pub async fn from_transport(mut transport: T, alt: u8) -> Result<Self, Error> {
+        let device = DfuDevice::from_transport(&mut transport).await?;
+        let mut dfu = Dfu::setup(transport, device, alt)?;
         dfu.abort_to_idle_clear_once().await?;
         Ok(dfu)
     }
 
+    /// Open the alt setting named `name`, e.g. "Option Bytes", whatever its
+    /// number is on this device.
+    pub async fn from_transport_alt_name(mut transport: T, name: &str) -> Result<Self, Error> {
+        let device = DfuDevice::from_transport(&mut transport).await?;
+        let alt = device.resolve(&AltSelector::from(name))?;
+        let mut dfu = Dfu::setup(transport, device, alt)?;
+        dfu.abort_to_idle_clear_once().await?;
+        Ok(dfu)
+    }
+
+    /// Get status, waiting first for the poll timeout of the previous status.
     pub async fn get_status(&mut self, mut retries: u8) -> Result<Status, Error> {
         let mut status = Err(Error::Argument("Get status retries failed".into()));
         retries += 1;
+        if let Some(next_poll) = self.next_poll.take() {
+            timer::sleep_until(next_poll).await;
+        }
         while retries > 0 {
             retries -= 1;
-            status = Status::get(&self.interface).await;
+            status = Status::get(&mut self.transport).await;
             if let Err(e) = &status {
                 if let Error::USB(_, e) = e {
                     if e.kind() == std::io::ErrorKind::BrokenPipe {
                         log::warn!("Epipe try again");
-                        tokio::time::sleep(std::time::Duration::from_millis(3000)).await;
+                        timer::sleep(self.poll_limits.pipe_retry).await;
                         continue;
                     }
                 } else if let Error::InvalidControlResponse(e) = e {
                     log::warn!("retries {} Get status error cause '{}'", retries, e);
-                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    timer::sleep(self.poll_limits.retry).await;
                     continue;
                 }
             } else {
                 retries = 0;
             }
         }
+        if let Ok(s) = &status {
+            self.next_poll = Some(Instant::now() + self.poll_limits.delay(s));
+        }
         status
     }
 
     pub async fn clear_status(&mut self) -> Result<(), Error> {
-        self.interface.control_out(ControlOut {
-            control_type: ControlType::Class,
-            recipient: Recipient::Interface,
-            request: DFU_CLRSTATUS,
-            value: 0,
-            index: self.interface.interface_number() as u16,
-            data: &[],
-        }).await.into_result().map_err(|e| Error::USB("Control transfer".into(), e.into()))?;
+        self.transport.control_out(DFU_CLRSTATUS, 0, &[]).await.map_err(|e| Error::USB("Control transfer".into(), e))?;
         Ok(())
     }
 
     pub async fn detach(&mut self) -> Result<(), Error> {
-        self.interface.control_out(ControlOut {
-            control_type: ControlType::Class,
-            recipient: Recipient::Interface,
-            request: DFU_DETACH,
-            value: 0,
-            index: self.interface.interface_number() as u16,
-            data: &[],
-        }).await.into_result().map_err(|e| Error::USB("Detach".into(), e.into()))?;
+        let timeout = self.device.descriptor.detach_timeout.as_millis().min(u16::MAX as u128) as u16;
+        self.transport.control_out(DFU_DETACH, timeout, &[]).await.map_err(|e| Error::USB("Detach".into(), e))?;
+        self.detached = true;
         Ok(())
     }
 
+    /// Poll status until the device reports `wait_for_state`. The status is
+    /// read at least twice, and polling stops once `timeout` has passed.
     pub async fn status_wait_for(
         &mut self,
-        mut retries: u8,
+        timeout: Duration,
         wait_for_state: Option<State>,
     ) -> Result<Status, Error> {
-        retries += 1;
         let wait_for_state = if let Some(wait_for_state) = wait_for_state {
             wait_for_state
         } else {
             State::DfuDownloadBusy
         };
+        let deadline = Instant::now() + timeout;
         let mut s = self.get_status(10).await?;
-        while retries > 0 {
-            if s.state == u8::from(&wait_for_state) {
-                break;
-            }
-            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-            retries -= 1;
+        if s.state != u8::from(&wait_for_state) {
             s = self.get_status(10).await?;
+            while s.state != u8::from(&wait_for_state) && Instant::now() < deadline {
+                s = self.get_status(10).await?;
+            }
         }
 
         // check if expected state and return fail if not
@@ -271,35 +416,90 @@ impl Dfu {
             return Err(Error::InvalidState(s, wait_for_state));
         }
 
-        if s.status != 0 {
-            return Err(Error::InvalidStatus(s, 0));
+        if s.status != StatusCode::Ok {
+            return Err(Error::InvalidStatus(s, StatusCode::Ok));
         }
         Ok(s)
     }
 
     pub async fn set_address(&mut self, address: u32) -> Result<(), Error> {
+        self.require_dfuse("Set address")?;
         self.dfuse_download(Vec::from(DfuseCommand::SetAddress(address)), 0).await?;
-        self.status_wait_for(0, Some(State::DfuDownloadIdle)).await?;
+        self.status_wait_for(Duration::ZERO, Some(State::DfuDownloadIdle)).await?;
         Ok(())
     }
 
     pub async fn reset_stm32(&mut self, address: u32) -> Result<(), Error> {
+        self.require_dfuse("Reset")?;
         //self.abort_to_idle()?;
         self.set_address(address).await?;
         log::debug!("set done");
-        //        self.abort_to_idle()?;
-        //       log::debug!("abort done");
-        self.dfuse_download(Vec::new(), 2).await?;
-        log::debug!("dfuse None, 2 done");
-        self.get_status(0).await.unwrap_or_else(|e| {
-            log::warn!("get_status failed cause {}", e);
-            Status::default()
-        });
-        self.detached = true;
+        let m = self.manifest().await?;
+        log::debug!("Manifestation {:?}", m);
         Ok(())
     }
 
+    /// Run the manifestation phase after a download: send the zero length
+    /// DFU_DNLOAD, poll through dfuMANIFEST-SYNC and dfuMANIFEST and issue a
+    /// USB reset if the device ends in dfuMANIFEST-WAIT-RESET.
+    /// The device must be in dfuDNLOAD-IDLE, on DfuSe devices the address set
+    /// last is where the device jumps to. Manifestation taking longer than
+    /// the poll limits' timeout fails with `Error::InvalidState`.
+    pub async fn manifest(&mut self) -> Result<Manifestation, Error> {
+        self.require_download("Manifest")?;
+        let tracker = Tracker::start(&mut self.progress, Phase::Manifest, 0, 0);
+        let m = self.manifest_phase().await?;
+        tracker.end(&mut self.progress);
+        Ok(m)
+    }
+
+    async fn manifest_phase(&mut self) -> Result<Manifestation, Error> {
+        let block = match self.protocol {
+            Protocol::Dfu => self.next_block,
+            // DfuSe leaves DFU mode on an empty download from block 2 on
+            Protocol::Dfuse => 2,
+        };
+        self.transport.control_out(DFU_DNLOAD, block, &[]).await
+            .map_err(|e| Error::USB("Dfu download".into(), e))?;
+        let tolerant = self.device.descriptor.manifestation_tolerant;
+        let mut manifesting = false;
+        let deadline = Instant::now() + self.poll_limits.timeout;
+        loop {
+            let s = match self.get_status(10).await {
+                Ok(s) => s,
+                // a device which isn't manifestation tolerant may be gone
+                // before it can report dfuMANIFEST-WAIT-RESET
+                Err(e) if manifesting && !tolerant => {
+                    log::debug!("Device left during manifestation cause {}", e);
+                    self.detached = true;
+                    return Ok(Manifestation::Reset);
+                }
+                Err(e) => return Err(e),
+            };
+            if s.status != StatusCode::Ok {
+                return Err(Error::InvalidStatus(s, StatusCode::Ok));
+            }
+            match State::from(s.state) {
+                State::DfuManifestSync | State::DfuManifest if Instant::now() >= deadline => {
+                    let expected = if tolerant { State::DfuIdle } else { State::DfuManifestWaitReset };
+                    return Err(Error::InvalidState(s, expected));
+                }
+                State::DfuManifestSync => {}
+                State::DfuManifest => manifesting = true,
+                State::DfuIdle => return Ok(Manifestation::StayedInDfu),
+                State::DfuManifestWaitReset => {
+                    self.detached = true;
+                    self.transport.reset().map_err(|e| Error::USB("Reset".into(), e))?;
+                    return Ok(Manifestation::Reset);
+                }
+                _ => return Err(Error::InvalidState(s, State::DfuManifestSync)),
+            }
+        }
+    }
+
     pub async fn dfuse_get_commands(&mut self) -> Result<Vec<DfuseCommand>, Error> {
+        self.require_dfuse("Get commands")?;
+        self.require_upload("Get commands")?;
         self.abort_to_idle().await?;
         let mut v = Vec::new();
         let cmds = &self.dfuse_upload(0, 1024).await?;
@@ -317,21 +517,34 @@ impl Dfu {
         Ok(v)
     }
 
-    /// Verify flash using file
-    pub async fn verify(
+    /// Verify flash against `reader`.
+    /// If length is None everything left in `reader` is compared, otherwise
+    /// `reader` must hold at least `length` bytes.
+    pub async fn verify<R: Read + Seek>(
         &mut self,
-        file: &mut File,
+        reader: &mut R,
         address: u32,
-        length: u32,
+        length: impl Into<Option<u32>>,
     ) -> Result<(), Error> {
-        self.dfuse_download(Vec::from(DfuseCommand::SetAddress(address)), 0).await?;
-        self.status_wait_for(0, None).await?;
-        self.abort_to_idle().await?;
-        self.status_wait_for(0, Some(State::DfuIdle)).await?;
-        let mut t = Transaction::new(address, length, self.dfu_descriptor.transfer_size);
-        while t.xfer > 0 {
-            let address = t.address;
-            self.flash_read_chunk(&mut t, |v| {
+        self.require_upload("Verify")?;
+        let length = stream_length(reader, length.into())?;
+        self.verify_length(reader, address, length).await
+    }
+
+    /// Verify flash against `length` bytes of a reader that can't seek, such
+    /// as stdin.
+    pub async fn verify_stream<R: Read>(&mut self, reader: &mut R, address: u32, length: u32) -> Result<(), Error> {
+        self.verify_length(reader, address, length).await
+    }
+
+    async fn verify_length<R: Read>(&mut self, file: &mut R, address: u32, length: u32) -> Result<(), Error> {
+        self.require_upload("Verify")?;
+        if self.protocol == Protocol::Dfu {
+            return self.dfu_verify(file, length).await;
+        }
+        self.mem_layout.check(address, length, Access::Read)?;
+        let res = self
+            .dfuse_read(address, length, Phase::Verify, |address, v| {
                 let mut r = vec![0; v.len()];
                 file.read_exact(&mut r)?;
                 let mut i2 = v.iter();
@@ -347,121 +560,231 @@ impl Dfu {
                     return Err(Error::Verify(address + v.len() as u32));
                 }
                 Ok(())
-            }).await?;
-        }
-        self.abort_to_idle().await?;
-        Ok(())
+            })
+            .await;
+        self.read_error(res).await
+    }
+
+    /// Sectors `erase_pages` would erase for the region, nothing is sent.
+    pub fn erase_plan(&self, address: u32, length: u32) -> Result<Vec<EraseSector>, Error> {
+        self.mem_layout.erase_plan(address, length)
     }
 
     /// Erase pages from start address + length
-    pub async fn erase_pages(&mut self, mut address: u32, length: u32) -> Result<(), Error> {
-        self.status_wait_for(0, Some(State::DfuIdle)).await?;
-        let mut pages = self.mem_layout.num_pages(address, length)?;
-        let page = self.mem_layout.address(address)?;
-        // realign to beginning of page
-        address = page.address;
-        while pages > 0 {
-            self.dfuse_download(Vec::from(DfuseCommand::ErasePage(address)), 0).await?;
-            self.status_wait_for(0, Some(State::DfuDownloadBusy)).await?;
-            self.status_wait_for(100, Some(State::DfuDownloadIdle)).await?;
-            pages -= 1;
-            address += page.size;
+    pub async fn erase_pages(&mut self, address: u32, length: u32) -> Result<(), Error> {
+        self.require_dfuse("Erase")?;
+        let plan = self.erase_plan(address, length)?;
+        self.erase(&plan).await
+    }
+
+    /// Erase the sectors of a plan from `erase_plan`.
+    pub async fn erase(&mut self, plan: &[EraseSector]) -> Result<(), Error> {
+        self.require_dfuse("Erase")?;
+        self.require_download("Erase")?;
+        for sector in plan {
+            self.mem_layout.check(sector.address, sector.size, Access::Erase)?;
+        }
+        self.status_wait_for(Duration::ZERO, Some(State::DfuIdle)).await?;
+        let address = plan.first().map(|s| s.address).unwrap_or_default();
+        let total = plan.iter().map(|s| s.size).sum();
+        let mut tracker = Tracker::start(&mut self.progress, Phase::Erase, address, total);
+        for sector in plan {
+            self.check_cancel(sector.address).await?;
+            self.dfuse_download(Vec::from(DfuseCommand::ErasePage(sector.address)), 0).await?;
+            self.status_wait_for(Duration::ZERO, Some(State::DfuDownloadBusy)).await?;
+            self.status_wait_for(self.poll_limits.timeout, Some(State::DfuDownloadIdle)).await?;
+            if let Some(o) = &mut self.progress {
+                o.progress(&Progress::SectorErased(sector.clone()));
+            }
+            tracker.add(&mut self.progress, sector.size);
         }
+        tracker.end(&mut self.progress);
         Ok(())
     }
 
     /// Do mass erase of flash
     pub async fn mass_erase(&mut self) -> Result<(), Error> {
-        self.status_wait_for(0, Some(State::DfuIdle)).await?;
+        self.require_dfuse("Mass erase")?;
+        self.require_download("Mass erase")?;
+        self.status_wait_for(Duration::ZERO, Some(State::DfuIdle)).await?;
         self.dfuse_download(Vec::from(DfuseCommand::MassErase), 0).await?;
-        self.status_wait_for(0, Some(State::DfuDownloadBusy)).await?;
-        self.status_wait_for(10, Some(State::DfuDownloadIdle)).await?;
+        self.status_wait_for(Duration::ZERO, Some(State::DfuDownloadBusy)).await?;
+        self.status_wait_for(self.poll_limits.mass_erase_timeout, Some(State::DfuDownloadIdle)).await?;
         Ok(())
     }
 
-    async fn flash_read_chunk<F>(&mut self, t: &mut Transaction, mut f: F) -> Result<(), Error>
+    /// DfuSe upload of `length` bytes from `address`, handing each chunk to
+    /// `f` along with its address.
+    pub(crate) async fn dfuse_read<F>(
+        &mut self,
+        address: u32,
+        length: u32,
+        phase: Phase,
+        mut f: F,
+    ) -> Result<(), Error>
     where
-        F: FnMut(Vec<u8>) -> Result<(), Error>,
+        F: FnMut(u32, Vec<u8>) -> Result<(), Error>,
     {
-        log::debug!("{:X?}", t);
-        let v = self.dfuse_upload(t.transaction, t.xfer).await?;
-        f(v)?;
-        let _ = t.next().is_some();
+        let mut tracker = Tracker::start(&mut self.progress, phase, address, length);
+        let mut t = Transaction::new(address, length, self.device.descriptor.transfer_size);
+        while t.xfer > 0 {
+            self.check_cancel(tracker.address()).await?;
+            if t.transaction == 2 {
+                if t.address != address {
+                    // leave dfuUPLOAD-IDLE for the new address
+                    self.abort_to_idle().await?;
+                }
+                self.dfuse_download(Vec::from(DfuseCommand::SetAddress(t.base)), 0).await?;
+                self.status_wait_for(Duration::ZERO, None).await?;
+                self.abort_to_idle().await?;
+                self.status_wait_for(Duration::ZERO, Some(State::DfuIdle)).await?;
+            }
+            log::debug!("{:X?}", t);
+            let v = self.dfuse_upload(t.transaction, t.xfer).await?;
+            f(t.address, v)?;
+            tracker.add(&mut self.progress, t.xfer as u32);
+            let _ = t.next().is_some();
+        }
+        self.abort_to_idle().await?;
+        tracker.end(&mut self.progress);
         Ok(())
     }
 
-    pub async fn write_flash_from_slice(&mut self, address: u32, buf: &[u8]) -> Result<usize, Error> {
-        let mut length = buf.len() as u32;
-        self.erase_pages(address, length).await?;
-        self.abort_to_idle().await?;
-        self.status_wait_for(0, Some(State::DfuIdle)).await?;
-        let mut transaction = 2;
-        let mut xfer;
-        if length >= self.dfu_descriptor.transfer_size as u32 {
-            panic!(
-                "FIXME write_flash_from_slice only allow xfer size max {}",
-                self.dfu_descriptor.transfer_size
-            );
+    /// Pass on the result of a read, telling a device that refuses the
+    /// upload because of readout protection apart from other failures.
+    async fn read_error(&mut self, res: Result<(), Error>) -> Result<(), Error> {
+        match res {
+            Err(e @ (Error::USB(_, _) | Error::InvalidState(_, _) | Error::InvalidStatus(_, _)))
+                if self.device.find(OPTION_BYTES_ALT).is_some() =>
+            {
+                // the failed upload leaves the device in dfuERROR
+                if self.abort_to_idle_clear_once().await.is_err() {
+                    return Err(e);
+                }
+                match self.read_protection().await {
+                    Ok(Some(reason)) => Err(Error::ReadProtected(reason)),
+                    _ => Err(e),
+                }
+            }
+            res => res,
         }
-        while length != 0 {
-            if length >= self.dfu_descriptor.transfer_size as u32 {
-                xfer = self.dfu_descriptor.transfer_size;
-                length -= self.dfu_descriptor.transfer_size as u32;
-            } else {
-                xfer = length as u16;
-                length = 0;
+    }
+
+    /// Why the device refuses reads, None if it is not readout protected.
+    ///
+    /// The level comes from the option bytes; a device with an option bytes
+    /// alt setting that can't be read from is taken as protected.
+    pub async fn read_protection(&mut self) -> Result<Option<String>, Error> {
+        self.require_dfuse("Read protection")?;
+        if self.device.find(OPTION_BYTES_ALT).is_none() {
+            return Ok(None);
+        }
+        let alt = self.alt;
+        match self.read_option_bytes().await {
+            Ok(ob) if ob.rdp_level() == RdpLevel::Level0 => Ok(None),
+            Ok(ob) => Ok(Some(ob.rdp_level().to_string())),
+            Err(Error::OptionBytes(e)) => Err(Error::OptionBytes(e)),
+            Err(e) => {
+                // the failed read may have left the option bytes alt setting
+                // selected
+                self.select_alt(alt).await?;
+                Ok(Some(format!("option bytes unreadable: {}", e)))
             }
-            log::debug!(
-                "{}: 0x{:4X} xfer: {} length: {}",
-                transaction,
-                address,
-                xfer,
-                length
-            );
-            self.dfuse_download(Vec::from(DfuseCommand::SetAddress(address)), 0).await?;
-            self.status_wait_for(100, Some(State::DfuDownloadIdle)).await?;
-            self.dfuse_download(buf.into(), transaction).await?;
-            self.status_wait_for(100, Some(State::DfuDownloadBusy)).await?;
-            self.status_wait_for(100, Some(State::DfuDownloadIdle)).await?;
-            transaction += 1;
         }
-        self.abort_to_idle().await?;
-        Ok(length as usize)
+    }
+
+    /// Remove readout protection with the DfuSe Read Unprotect command.
+    ///
+    /// The device mass erases its flash and resets itself, then it is opened
+    /// again on the same alt setting. Everything in flash is lost.
+    pub async fn read_unprotect(&mut self) -> Result<(), Error> {
+        self.require_dfuse("Read unprotect")?;
+        self.require_download("Read unprotect")?;
+        self.abort_to_idle_clear_once().await?;
+        self.dfuse_download(Vec::from(DfuseCommand::ReadUnprotected), 0).await?;
+        // the device mass erases and resets, the status poll that starts it
+        // is the last one answered
+        if let Err(e) = self.wait_while_busy().await {
+            log::debug!("Read unprotect ended with {}", e);
+        }
+        self.transport
+            .reconnect()
+            .await
+            .map_err(|e| Error::USB("Reconnect after read unprotect".into(), e))?;
+        self.next_poll = None;
+        self.transport
+            .set_alt_setting(self.alt)
+            .map_err(|e| Error::USB("Set alt setting".into(), e))?;
+        self.abort_to_idle_clear_once().await
+    }
+
+    /// Erase and write `buf` from `address` on, returning the number of
+    /// bytes written. Neither `address` nor the length has to be aligned to
+    /// sectors or the transfer size: the rest of the first and last sector
+    /// is read before the erase and written back with `buf`, so whole
+    /// sectors are programmed and nothing around `buf` is lost.
+    pub async fn write_flash_from_slice(&mut self, address: u32, buf: &[u8]) -> Result<usize, Error> {
+        self.require_dfuse("Write to address")?;
+        let length = u32::try_from(buf.len())
+            .map_err(|_| Error::Argument("Buffer is larger than 4 GiB".into()))?;
+        self.require_download("Write to address")?;
+        self.mem_layout.check(address, length, Access::Write)?;
+        self.mem_layout.check(address, length, Access::Erase)?;
+        let plan = self.erase_plan(address, length)?;
+        let (Some(first), Some(last)) = (plan.first(), plan.last()) else {
+            return Ok(0);
+        };
+        let start = first.address;
+        let end = last.address as u64 + last.size as u64;
+        let tail_start = address as u64 + length as u64;
+        let mut image = vec![0; (address - start) as usize];
+        if !image.is_empty() {
+            self.read_flash_to_slice(start, &mut image).await?;
+        }
+        image.extend_from_slice(buf);
+        if tail_start < end {
+            let mut tail = vec![0; (end - tail_start) as usize];
+            self.read_flash_to_slice(tail_start as u32, &mut tail).await?;
+            image.extend(tail);
+        }
+        let total = u32::try_from(image.len())
+            .map_err(|_| Error::Argument("Sectors to write are larger than 4 GiB".into()))?;
+        self.download_length(&mut image.as_slice(), start, total).await?;
+        Ok(buf.len())
     }
 
     pub async fn read_flash_to_slice(&mut self, address: u32, buf: &mut [u8]) -> Result<usize, Error> {
-        self.dfuse_download(Vec::from(DfuseCommand::SetAddress(address)), 0).await?;
-        self.status_wait_for(0, None).await?;
-        self.abort_to_idle().await?;
-        self.status_wait_for(0, Some(State::DfuIdle)).await?;
+        self.require_dfuse("Read from address")?;
+        self.require_upload("Read from address")?;
+        self.mem_layout.check(address, buf.len() as u32, Access::Read)?;
         let mut len = 0;
-        let size = buf.len();
-        let mut t = Transaction::new(address, size as u32, self.dfu_descriptor.transfer_size);
-        while t.xfer > 0 {
-            self.flash_read_chunk(&mut t, |v| {
+        let size = buf.len() as u32;
+        let res = self
+            .dfuse_read(address, size, Phase::Read, |_, v| {
                 for b in v {
                     buf[len] = b;
                     len += 1;
                 }
                 Ok(())
-            }).await?;
-        }
-        self.abort_to_idle().await?;
+            })
+            .await;
+        self.read_error(res).await?;
         Ok(len)
     }
 
-    /// Upload read flash and store it in file.
-    pub async fn upload(&mut self, file: &mut File, address: u32, length: u32) -> Result<(), Error> {
-        self.dfuse_download(Vec::from(DfuseCommand::SetAddress(address)), 0).await?;
-        self.status_wait_for(0, None).await?;
-        self.abort_to_idle().await?;
-        self.status_wait_for(0, Some(State::DfuIdle)).await?;
-        let mut t = Transaction::new(address, length, self.dfu_descriptor.transfer_size);
-        while t.xfer > 0 {
-            self.flash_read_chunk(&mut t, |v| Ok(file.write_all(&v)?)).await?;
+    /// Upload read flash and write it to `file`.
+    /// On a plain DFU device the address is ignored and a length of 0 reads
+    /// until the device ends the upload with a short packet.
+    pub async fn upload<W: Write>(&mut self, file: &mut W, address: u32, length: u32) -> Result<(), Error> {
+        self.require_upload("Upload")?;
+        if self.protocol == Protocol::Dfu {
+            return self.dfu_upload(length, Phase::Read, |v| Ok(file.write_all(&v)?)).await;
         }
-        self.abort_to_idle().await?;
-        Ok(())
+        self.mem_layout.check(address, length, Access::Read)?;
+        let res = self
+            .dfuse_read(address, length, Phase::Read, |_, v| Ok(file.write_all(&v)?))
+            .await;
+        self.read_error(res).await
     }
 
     pub async fn abort_to_idle_clear_once(&mut self) -> Result<(), Error> {
@@ -471,14 +794,7 @@ impl Dfu {
             return Ok(());
         }
 
-        self.interface.control_out(ControlOut {
-            control_type: ControlType::Class,
-            recipient: Recipient::Interface,
-            request: DFU_ABORT,
-            value: 0,
-            index: self.interface.interface_number() as u16,
-            data: &[],
-        }).await.into_result().map_err(|e| Error::USB("Abort to idle".into(), e.into()))?;
+        self.transport.control_out(DFU_ABORT, 0, &[]).await.map_err(|e| Error::USB("Abort to idle".into(), e))?;
     
         let s = self.get_status(0).await?;
         // try clear and read again in case of wrong state
@@ -492,14 +808,7 @@ impl Dfu {
     }
 
     pub async fn abort_to_idle(&mut self) -> Result<(), Error> {
-        self.interface.control_out(ControlOut {
-            control_type: ControlType::Class,
-            recipient: Recipient::Interface,
-            request: DFU_ABORT,
-            value: 0,
-            index: self.interface.interface_number() as u16,
-            data: &[],
-        }).await.into_result().map_err(|e| Error::USB("Abort to idle".into(), e.into()))?;
+        self.transport.control_out(DFU_ABORT, 0, &[]).await.map_err(|e| Error::USB("Abort to idle".into(), e))?;
 
         let s = self.get_status(0).await?;
         if s.state != u8::from(&State::DfuIdle) {
@@ -508,68 +817,172 @@ impl Dfu {
         Ok(())
     }
 
-    /// Download file to device using raw mode.
-    /// If length is None it will read to file end.
-    pub async fn download_raw(
+    /// Download from `reader` to device using raw mode, one transfer at a
+    /// time.
+    /// If length is None everything left in `reader` is written, otherwise
+    /// `reader` must hold at least `length` bytes. Both are known before
+    /// anything is erased.
+    /// On a plain DFU device the address is ignored and the download ends
+    /// with the manifestation phase.
+    pub async fn download_raw<R: Read + Seek>(
         &mut self,
-        file: &mut File,
+        reader: &mut R,
         address: u32,
-        mut length: u32,
+        length: impl Into<Option<u32>>,
     ) -> Result<(), Error> {
+        self.require_download("Download")?;
+        let length = stream_length(reader, length.into())?;
+        self.download_length(reader, address, length).await
+    }
+
+    /// Download `length` bytes from a reader that can't seek, such as stdin
+    /// or a socket. A reader that ends early is only noticed when its data
+    /// runs out, after the sectors were erased.
+    pub async fn download_stream<R: Read>(&mut self, reader: &mut R, address: u32, length: u32) -> Result<(), Error> {
+        self.download_length(reader, address, length).await
+    }
+
+    async fn download_length<R: Read>(&mut self, file: &mut R, address: u32, length: u32) -> Result<(), Error> {
+        self.require_download("Download")?;
+        if self.protocol == Protocol::Dfu {
+            return self.dfu_download(file, length).await;
+        }
+        self.mem_layout.check(address, length, Access::Write)?;
         self.erase_pages(address, length).await?;
         self.abort_to_idle().await?;
-        self.status_wait_for(0, Some(State::DfuIdle)).await?;
-        let mut transaction = 2;
-        let mut xfer;
+        self.status_wait_for(Duration::ZERO, Some(State::DfuIdle)).await?;
+        let mut tracker = Tracker::start(&mut self.progress, Phase::Write, address, length);
+        let mut t = Transaction::new(address, length, self.device.descriptor.transfer_size);
+        while t.xfer > 0 {
+            self.check_cancel(tracker.address()).await?;
+            log::debug!("{:X?}", t);
+            let mut buf = vec![0; t.xfer as usize];
+            file.read_exact(&mut buf)?;
+            self.dfuse_download(Vec::from(DfuseCommand::SetAddress(t.base)), 0).await?;
+            self.status_wait_for(self.poll_limits.timeout, Some(State::DfuDownloadIdle)).await?;
+            self.dfuse_download(buf, t.transaction).await?;
+            self.status_wait_for(self.poll_limits.timeout, Some(State::DfuDownloadBusy)).await?;
+            self.status_wait_for(self.poll_limits.timeout, Some(State::DfuDownloadIdle)).await?;
+            tracker.add(&mut self.progress, t.xfer as u32);
+            let _ = t.next().is_some();
+        }
+        self.abort_to_idle().await?;
+        tracker.end(&mut self.progress);
+        Ok(())
+    }
+
+    /// Plain DFU download, blocks numbered from 0.
+    async fn dfu_download<R: Read>(&mut self, file: &mut R, mut length: u32) -> Result<(), Error> {
+        self.status_wait_for(Duration::ZERO, Some(State::DfuIdle)).await?;
+        let mut tracker = Tracker::start(&mut self.progress, Phase::Write, 0, length);
+        let mut block: u16 = 0;
         while length != 0 {
-            if length >= self.dfu_descriptor.transfer_size as u32 {
-                xfer = self.dfu_descriptor.transfer_size;
-                length -= self.dfu_descriptor.transfer_size as u32;
-            } else {
-                xfer = length as u16;
-                length = 0;
-            }
-            log::debug!(
-                "{}: 0x{:4X} xfer: {} length: {}",
-                transaction,
-                address,
-                xfer,
-                length
-            );
+            self.check_cancel(tracker.address()).await?;
+            let xfer = length.min(self.device.descriptor.transfer_size as u32);
+            length -= xfer;
+            log::debug!("{}: xfer: {} length: {}", block, xfer, length);
             let mut buf = vec![0; xfer as usize];
             file.read_exact(&mut buf)?;
-            self.dfuse_download(Vec::from(DfuseCommand::SetAddress(address)), 0).await?;
-            self.status_wait_for(100, Some(State::DfuDownloadIdle)).await?;
-            self.dfuse_download(buf, transaction).await?;
-            self.status_wait_for(100, Some(State::DfuDownloadBusy)).await?;
-            self.status_wait_for(100, Some(State::DfuDownloadIdle)).await?;
-            transaction += 1;
+            self.transport.control_out(DFU_DNLOAD, block, &buf).await
+                .map_err(|e| Error::USB("Dfu download".into(), e))?;
+            let s = self.wait_while_busy().await?;
+            if s.state != u8::from(&State::DfuDownloadIdle) {
+                return Err(Error::InvalidState(s, State::DfuDownloadIdle));
+            }
+            tracker.add(&mut self.progress, xfer);
+            block = block.wrapping_add(1);
+        }
+        tracker.end(&mut self.progress);
+        self.next_block = block;
+        self.manifest().await?;
+        Ok(())
+    }
+
+    /// Poll status until the device leaves the download sync and busy
+    /// states, for at most the poll limits' timeout.
+    async fn wait_while_busy(&mut self) -> Result<Status, Error> {
+        let deadline = Instant::now() + self.poll_limits.timeout;
+        loop {
+            let s = self.get_status(10).await?;
+            if s.status != StatusCode::Ok {
+                return Err(Error::InvalidStatus(s, StatusCode::Ok));
+            }
+            if !matches!(
+                State::from(s.state),
+                State::DfuDownloadSync | State::DfuDownloadBusy
+            ) {
+                return Ok(s);
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::InvalidState(s, State::DfuDownloadIdle));
+            }
+        }
+    }
+
+    /// Plain DFU upload, blocks numbered from 0 until a short packet or
+    /// `length` bytes if not 0.
+    async fn dfu_upload<F>(&mut self, length: u32, phase: Phase, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(Vec<u8>) -> Result<(), Error>,
+    {
+        self.status_wait_for(Duration::ZERO, Some(State::DfuIdle)).await?;
+        let mut tracker = Tracker::start(&mut self.progress, phase, 0, length);
+        let xfer_max = self.device.descriptor.transfer_size as u32;
+        let mut block: u16 = 0;
+        let mut pending = length;
+        loop {
+            self.check_cancel(tracker.address()).await?;
+            let xfer = if length == 0 { xfer_max } else { pending.min(xfer_max) };
+            let v = self.dfuse_upload(block, xfer as u16).await?;
+            let short = (v.len() as u32) < xfer;
+            pending = pending.saturating_sub(v.len() as u32);
+            tracker.add(&mut self.progress, v.len() as u32);
+            f(v)?;
+            if short {
+                // a short packet ends the upload and the device is idle again
+                tracker.end(&mut self.progress);
+                return Ok(());
+            }
+            if length != 0 && pending == 0 {
+                break;
+            }
+            block = block.wrapping_add(1);
         }
         self.abort_to_idle().await?;
+        tracker.end(&mut self.progress);
+        Ok(())
+    }
+
+    async fn dfu_verify<R: Read>(&mut self, file: &mut R, length: u32) -> Result<(), Error> {
+        let mut offset = 0;
+        self.dfu_upload(length, Phase::Verify, |v| {
+            let mut r = vec![0; v.len()];
+            file.read_exact(&mut r)?;
+            if let Some(i) = r.iter().zip(v.iter()).position(|(a, b)| a != b) {
+                return Err(Error::Verify(offset + i as u32));
+            }
+            offset += v.len() as u32;
+            Ok(())
+        }).await?;
+        if offset != length {
+            return Err(Error::Verify(offset));
+        }
         Ok(())
     }
 
     async fn dfuse_download(&mut self, buf: Vec<u8>, transaction: u16) -> Result<(), Error> {
-        let res = self.interface.control_out(ControlOut {
-            control_type: ControlType::Class,
-            recipient: Recipient::Interface,
-            request: DFU_DNLOAD,
-            value: transaction,
-            index: self.interface.interface_number() as u16,
-            data: &buf,
-        }).await.into_result();
+        let res = self.transport.control_out(DFU_DNLOAD, transaction, &buf).await;
 
         match res
         {
             Err(e) => {
-                match e {
-                    nusb::transfer::TransferError::Stall => {
-                        log::warn!("stalled on transaction {}", transaction);
-                        self.abort_to_idle().await?;
-                        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
-                        Ok(())
-                    }
-                    _ => Err(Error::USB("Dfuse download".into(), e.into())),
+                if is_stall(&e) {
+                    log::warn!("stalled on transaction {}", transaction);
+                    self.abort_to_idle().await?;
+                    timer::sleep(std::time::Duration::from_millis(10)).await;
+                    Ok(())
+                } else {
+                    Err(Error::USB("Dfuse download".into(), e))
                 }
             }
             Ok(_) => Ok(()),
@@ -577,28 +990,72 @@ impl Dfu {
     }
 
 
+    /// Write `buf` from `address` on without erasing first, for areas the
+    /// device programs in place such as option bytes and OTP.
+    pub(crate) async fn dfuse_write(&mut self, address: u32, buf: &[u8]) -> Result<(), Error> {
+        self.require_dfuse("Write")?;
+        self.require_download("Write")?;
+        self.mem_layout.check(address, buf.len() as u32, Access::Write)?;
+        self.status_wait_for(Duration::ZERO, Some(State::DfuIdle)).await?;
+        let mut tracker = Tracker::start(&mut self.progress, Phase::Write, address, buf.len() as u32);
+        let mut t = Transaction::new(address, buf.len() as u32, self.device.descriptor.transfer_size);
+        while t.xfer > 0 {
+            if t.transaction == 2 {
+                self.set_address(t.base).await?;
+            }
+            log::debug!("{:X?}", t);
+            let offset = (t.address - address) as usize;
+            let chunk = buf[offset..offset + t.xfer as usize].to_vec();
+            self.dfuse_download(chunk, t.transaction).await?;
+            let s = self.wait_while_busy().await?;
+            if s.state != u8::from(&State::DfuDownloadIdle) {
+                return Err(Error::InvalidState(s, State::DfuDownloadIdle));
+            }
+            tracker.add(&mut self.progress, t.xfer as u32);
+            let _ = t.next().is_some();
+        }
+        self.abort_to_idle().await?;
+        tracker.end(&mut self.progress);
+        Ok(())
+    }
+
     pub fn memory_layout(&self) -> &MemoryLayout {
         &self.mem_layout
     }
 
     async fn dfuse_upload(&mut self, transaction: u16, xfer: u16) -> Result<Vec<u8>, Error> {
-        let res = self.interface.control_in(ControlIn {
-            control_type: ControlType::Class,
-            recipient: Recipient::Interface,
-            request: DFU_UPLOAD,
-            value: transaction,
-            index: self.interface.interface_number() as u16,
-            length: xfer,
-        }).await.into_result();
+        let res = self.transport.control_in(DFU_UPLOAD, transaction, xfer).await;
 
         match res
         {
-            Err(e) => Err(Error::USB("Dfuse upload".into(), e.into())),
+            Err(e) => Err(Error::USB("Dfuse upload".into(), e)),
             Ok(buf) => Ok(buf),
         }
     }
 
-    pub fn usb(&mut self) -> &mut nusb::Device {
-        &mut self.usb
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+}
+
+/// Bytes left in `reader` from its position on, or `length` once checked
+/// against them. The position is restored, nothing is read.
+fn stream_length<R: Seek>(reader: &mut R, length: Option<u32>) -> Result<u32, Error> {
+    let start = reader.stream_position()?;
+    let end = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(start))?;
+    let left = end.saturating_sub(start);
+    match length {
+        Some(length) if left < length as u64 => Err(Error::Argument(format!(
+            "Image holds {} bytes, expected {}",
+            left, length
+        ))),
+        Some(length) => Ok(length),
+        None if left == 0 => Err(Error::Argument("Image is empty".into())),
+        None => u32::try_from(left).map_err(|_| Error::Argument("Image is larger than 4 GiB".into())),
     }
 }
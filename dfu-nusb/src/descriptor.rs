@@ -0,0 +1,165 @@
+use crate::core::Protocol;
+use crate::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+/// bcdDFUVersion of devices using the ST DfuSe extensions.
+pub const DFUSE_VERSION: u16 = 0x011A;
+
+pub(crate) const ATTR_CAN_DNLOAD: u8 = 1 << 0;
+pub(crate) const ATTR_CAN_UPLOAD: u8 = 1 << 1;
+pub(crate) const ATTR_MANIFESTATION_TOLERANT: u8 = 1 << 2;
+pub(crate) const ATTR_WILL_DETACH: u8 = 1 << 3;
+
+/// Binary coded decimal version as used by bcdDFUVersion, 0x0110 is 1.1.0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BcdVersion(pub u16);
+
+impl BcdVersion {
+    pub fn major(&self) -> u8 {
+        (self.0 >> 8) as u8
+    }
+
+    pub fn minor(&self) -> u8 {
+        ((self.0 >> 4) & 0x0F) as u8
+    }
+
+    /// Sub-minor digit, 0xA for DfuSe's 1.1a.
+    pub fn sub_minor(&self) -> u8 {
+        (self.0 & 0x0F) as u8
+    }
+}
+
+impl fmt::Display for BcdVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:x}.{:x}", self.major(), self.minor())?;
+        if self.sub_minor() != 0 {
+            write!(f, "{:x}", self.sub_minor())?;
+        }
+        Ok(())
+    }
+}
+
+/// DFU functional descriptor (DFU 1.1 section 4.1.3).
+#[derive(Debug, Clone, PartialEq)]
+pub struct DfuDescriptor {
+    /// bitCanDnload
+    pub can_download: bool,
+    /// bitCanUpload
+    pub can_upload: bool,
+    /// bitManifestationTolerant, device stays in DFU mode after manifestation.
+    pub manifestation_tolerant: bool,
+    /// bitWillDetach, device detaches itself on DFU_DETACH instead of
+    /// waiting for a USB reset.
+    pub will_detach: bool,
+    /// wDetachTimeout
+    pub detach_timeout: Duration,
+    /// wTransferSize
+    pub transfer_size: u16,
+    /// bcdDFUVersion, 1.0 if the descriptor is the short DFU 1.0 one.
+    pub dfu_version: BcdVersion,
+}
+
+impl DfuDescriptor {
+    pub fn new(desc: &[u8]) -> Result<Self, Error> {
+        let truncated = || Error::Descriptor(format!("Truncated {:02X?}", desc));
+        let mut iter = desc.iter();
+        // length, DFU 1.0 descriptors end before bcdDFUVersion
+        let length = *iter.next().ok_or_else(truncated)?;
+        if length != 7 && length != 9 {
+            return Err(Error::Descriptor(format!("Invalid length {}", length)));
+        }
+
+        // type
+        let descriptor_type = *iter.next().ok_or_else(truncated)?;
+        if descriptor_type != 33 {
+            return Err(Error::Descriptor(format!("Invalid type 0x{:02X}", descriptor_type)));
+        }
+
+        let mut next = || iter.next().copied().ok_or_else(truncated);
+        let attributes = next()?;
+        let detach_timeout = next()? as u16 | (next()? as u16) << 8;
+        let transfer_size = next()? as u16 | (next()? as u16) << 8;
+        let dfu_version = if length == 9 {
+            next()? as u16 | (next()? as u16) << 8
+        } else {
+            0x0100
+        };
+        if transfer_size == 0 {
+            return Err(Error::Descriptor("wTransferSize is 0".into()));
+        }
+
+        Ok(DfuDescriptor {
+            can_download: attributes & ATTR_CAN_DNLOAD != 0,
+            can_upload: attributes & ATTR_CAN_UPLOAD != 0,
+            manifestation_tolerant: attributes & ATTR_MANIFESTATION_TOLERANT != 0,
+            will_detach: attributes & ATTR_WILL_DETACH != 0,
+            detach_timeout: Duration::from_millis(detach_timeout as u64),
+            transfer_size,
+            dfu_version: BcdVersion(dfu_version),
+        })
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        if self.dfu_version.0 == DFUSE_VERSION {
+            Protocol::Dfuse
+        } else {
+            Protocol::Dfu
+        }
+    }
+}
+
+impl fmt::Display for DfuDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "DFU version: {}", self.dfu_version)?;
+        writeln!(f, "Transfer size: {} bytes", self.transfer_size)?;
+        writeln!(f, "Detach timeout: {} ms", self.detach_timeout.as_millis())?;
+        writeln!(f, "Can download: {}", self.can_download)?;
+        writeln!(f, "Can upload: {}", self.can_upload)?;
+        writeln!(f, "Manifestation tolerant: {}", self.manifestation_tolerant)?;
+        write!(f, "Will detach: {}", self.will_detach)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_dfu_descriptor() {
+        use super::{BcdVersion, DfuDescriptor};
+        use crate::core::Protocol;
+        use std::time::Duration;
+        // STM32 system bootloader
+        let d = DfuDescriptor::new(&[9, 0x21, 0x0B, 0xFF, 0x00, 0x00, 0x08, 0x1A, 0x01]).unwrap();
+        assert!(d.can_download);
+        assert!(d.can_upload);
+        assert!(!d.manifestation_tolerant);
+        assert!(d.will_detach);
+        assert_eq!(Duration::from_millis(255), d.detach_timeout);
+        assert_eq!(2048, d.transfer_size);
+        assert_eq!(BcdVersion(0x011A), d.dfu_version);
+        assert_eq!("1.1a", d.dfu_version.to_string());
+        assert_eq!(Protocol::Dfuse, d.protocol());
+
+        // download only, manifestation tolerant DFU 1.1
+        let d = DfuDescriptor::new(&[9, 0x21, 0x05, 0xE8, 0x03, 0x00, 0x10, 0x10, 0x01]).unwrap();
+        assert!(d.can_download);
+        assert!(!d.can_upload);
+        assert!(d.manifestation_tolerant);
+        assert!(!d.will_detach);
+        assert_eq!(Duration::from_secs(1), d.detach_timeout);
+        assert_eq!(4096, d.transfer_size);
+        assert_eq!("1.1", d.dfu_version.to_string());
+        assert_eq!(Protocol::Dfu, d.protocol());
+
+        // DFU 1.0 descriptor without bcdDFUVersion
+        let d = DfuDescriptor::new(&[7, 0x21, 0x03, 0x00, 0x00, 0x40, 0x00]).unwrap();
+        assert_eq!(BcdVersion(0x0100), d.dfu_version);
+
+        assert!(DfuDescriptor::new(&[9, 0x21, 0x0B, 0xFF, 0x00, 0x00, 0x08]).is_err());
+        assert!(DfuDescriptor::new(&[9, 0x04, 0x0B, 0xFF, 0x00, 0x00, 0x08, 0x1A, 0x01]).is_err());
+
+        // a zero wTransferSize would never move any data
+        let e = DfuDescriptor::new(&[9, 0x21, 0x0B, 0xFF, 0x00, 0x00, 0x00, 0x1A, 0x01]);
+        assert!(matches!(e, Err(crate::Error::Descriptor(_))));
+    }
+}
@@ -0,0 +1,151 @@
+use crate::core::Protocol;
+use crate::descriptor::DfuDescriptor;
+use crate::error::Error;
+use crate::memory_layout::MemoryLayout;
+use crate::transport::DfuTransport;
+use std::fmt;
+use std::str::FromStr;
+
+/// Alt setting picked by number or by name.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AltSelector {
+    Index(u8),
+    Name(String),
+}
+
+impl From<u8> for AltSelector {
+    fn from(alt: u8) -> Self {
+        AltSelector::Index(alt)
+    }
+}
+
+impl From<&str> for AltSelector {
+    fn from(name: &str) -> Self {
+        AltSelector::Name(name.to_string())
+    }
+}
+
+impl From<String> for AltSelector {
+    fn from(name: String) -> Self {
+        AltSelector::Name(name)
+    }
+}
+
+impl fmt::Display for AltSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AltSelector::Index(alt) => write!(f, "{}", alt),
+            AltSelector::Name(name) => write!(f, "'{}'", name),
+        }
+    }
+}
+
+/// One alternate setting of the DFU interface.
+#[derive(Debug, Clone)]
+pub struct AltSetting {
+    pub interface: u8,
+    pub alt: u8,
+    /// Name without the leading '@' and the DfuSe layout part, e.g.
+    /// "Option Bytes".
+    pub name: String,
+    /// Full string descriptor, empty if the alt setting has none.
+    pub string: String,
+    /// Memory layout of a DfuSe alt setting.
+    pub layout: Option<MemoryLayout>,
+}
+
+/// Everything a DFU interface describes about itself: the functional
+/// descriptor and every alternate setting.
+#[derive(Debug, Clone)]
+pub struct DfuDevice {
+    pub descriptor: DfuDescriptor,
+    pub alt_settings: Vec<AltSetting>,
+}
+
+impl DfuDevice {
+    /// Read the functional descriptor and the string of every alt setting.
+    pub async fn from_transport<T: DfuTransport>(transport: &mut T) -> Result<Self, Error> {
+        let descriptor = transport.functional_descriptor().ok_or_else(|| {
+            Error::DeviceNotFound("Missing configuration dfu transfer descriptor".to_string())
+        })?;
+        let descriptor = DfuDescriptor::new(&descriptor)?;
+        let interface = transport.interface_number();
+        let mut alt_settings = Vec::new();
+        for alt in transport.alt_settings() {
+            let string = match transport.alt_string_index(alt) {
+                Some(index) => transport
+                    .get_string_descriptor(index)
+                    .await
+                    .map_err(|e| Error::USB("Get string descriptor".into(), e))?,
+                None => String::new(),
+            };
+            let layout = match descriptor.protocol() {
+                Protocol::Dfuse => MemoryLayout::from_str(&string)
+                    .map_err(|e| log::debug!("Alt setting {} has no layout: {}", alt, e))
+                    .ok(),
+                Protocol::Dfu => None,
+            };
+            let name = match &layout {
+                Some(layout) => layout.name().to_string(),
+                None => {
+                    let name = string.split('/').next().unwrap_or_default();
+                    name.strip_prefix('@').unwrap_or(name).trim().to_string()
+                }
+            };
+            alt_settings.push(AltSetting {
+                interface,
+                alt,
+                name,
+                string,
+                layout,
+            });
+        }
+        Ok(Self {
+            descriptor,
+            alt_settings,
+        })
+    }
+
+    pub fn alt(&self, alt: u8) -> Option<&AltSetting> {
+        self.alt_settings.iter().find(|a| a.alt == alt)
+    }
+
+    /// Number of the alt setting `selector` picks.
+    pub fn resolve(&self, selector: &AltSelector) -> Result<u8, Error> {
+        let alt = match selector {
+            AltSelector::Index(alt) => self.alt(*alt),
+            AltSelector::Name(name) => self.find(name),
+        };
+        alt.map(|a| a.alt)
+            .ok_or_else(|| Error::Argument(format!("No alt setting {}", selector)))
+    }
+
+    /// Alt setting by name, ignoring surrounding blanks and case.
+    pub fn find(&self, name: &str) -> Option<&AltSetting> {
+        let name = name.trim();
+        self.alt_settings
+            .iter()
+            .find(|a| a.name.eq_ignore_ascii_case(name))
+    }
+}
+
+impl fmt::Display for DfuDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.descriptor)?;
+        for a in &self.alt_settings {
+            write!(f, "\nInterface {} alt {}: {}", a.interface, a.alt, a.name)?;
+            if let Some(layout) = &a.layout {
+                for s in layout.segments() {
+                    write!(
+                        f,
+                        "\n    0x{:08X} {} bytes in {} sectors",
+                        s.start,
+                        s.size(),
+                        s.pages.len()
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
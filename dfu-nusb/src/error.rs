@@ -1,4 +1,5 @@
-use crate::status::{State, Status};
+use crate::memory_layout::{Access, Page};
+use crate::status::{State, Status, StatusCode};
 use std::fmt;
 #[derive(Debug)]
 pub enum Error {
@@ -6,13 +7,21 @@ pub enum Error {
     Argument(String),
     InvalidControlResponse(String),
     InvalidState(Status, State),
-    InvalidStatus(Status, u8),
+    InvalidStatus(Status, StatusCode),
     USB(String, std::io::Error),
     FileIO(std::io::Error),
     UnknownCommandByte(u8),
     Address(u32),
     Verify(u32),
     MemoryLayout(String),
+    Trace(String),
+    Unsupported(String),
+    Permission(Page, Access),
+    OptionBytes(String),
+    ReadProtected(String),
+    Otp(String),
+    Cancelled(u32),
+    Descriptor(String),
 }
 
 impl From<std::io::Error> for Error {
@@ -36,6 +45,14 @@ impl From<Error> for i32 {
             Address(_) => 73,
             Verify(_) => 74,
             MemoryLayout(_) => 75,
+            Trace(_) => 76,
+            Unsupported(_) => 77,
+            Permission(_, _) => 78,
+            OptionBytes(_) => 79,
+            ReadProtected(_) => 80,
+            Otp(_) => 81,
+            Cancelled(_) => 82,
+            Descriptor(_) => 83,
         }
     }
 }
@@ -55,7 +72,7 @@ impl fmt::Display for Error {
             ),
             InvalidStatus(s, expect) => write!(
                 f,
-                "Invalid state Get status gave:\n{}\nExpected status: {}",
+                "Invalid status Get status gave:\n{}\nExpected status: {}",
                 s, expect
             ),
             FileIO(io) => write!(f, "IO error {}", io),
@@ -63,6 +80,25 @@ impl fmt::Display for Error {
             Address(a) => write!(f, "Address: 0x{:08X} not supported", a),
             Verify(a) => write!(f, "Verify failed at address: 0x{:08X}", a),
             MemoryLayout(s) => write!(f, "Could not get memory layout from '{}'", s),
+            Trace(s) => write!(f, "Invalid trace {}", s),
+            Unsupported(s) => write!(f, "Unsupported: {}", s),
+            Permission(p, access) => write!(
+                f,
+                "Sector 0x{:08X} ({} bytes, type '{}') is not {}",
+                p.address,
+                p.size,
+                p.permissions.letter(),
+                access
+            ),
+            OptionBytes(s) => write!(f, "Option bytes: {}", s),
+            ReadProtected(s) => write!(
+                f,
+                "Device is readout protected ({}), read-unprotect removes the protection by mass erasing",
+                s
+            ),
+            Otp(s) => write!(f, "OTP: {}", s),
+            Cancelled(a) => write!(f, "Cancelled, done up to address: 0x{:08X}", a),
+            Descriptor(s) => write!(f, "Invalid DFU functional descriptor: {}", s),
         }
     }
 }
@@ -0,0 +1,177 @@
+use crate::core::DFU_GET_STATUS;
+use crate::status::State;
+use crate::transport::DfuTransport;
+use nusb::transfer::TransferError;
+use std::io;
+
+/// Fault to inject in place of a normal control transfer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    /// Device stalls the request without acting on it.
+    Stall,
+    /// Request fails with `io::ErrorKind::BrokenPipe` without reaching the device.
+    BrokenPipe,
+    /// Request reaches the device but the answer is lost and the host sees
+    /// `io::ErrorKind::TimedOut`.
+    Timeout,
+    /// DFU_GETSTATUS answer is cut down to this many bytes.
+    ShortStatus(usize),
+    /// DFU_GETSTATUS reports dfuERROR with this status code, regardless of
+    /// the state the device is really in.
+    DfuError(u8),
+}
+
+/// When a fault fires.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trigger {
+    /// The nth control transfer of any kind, counting from 0.
+    Transfer(usize),
+    /// The nth control transfer with this bRequest, counting from 0.
+    Request(u8, usize),
+}
+
+/// `DfuTransport` wrapper that injects faults at chosen control transfers.
+///
+/// Each fault fires once. Transfers not hit by a fault are passed on to the
+/// wrapped transport unchanged.
+pub struct FaultInjector<T: DfuTransport> {
+    inner: T,
+    faults: Vec<(Trigger, Fault)>,
+    transfers: usize,
+    requests: [usize; 256],
+    injected: Vec<(usize, Fault)>,
+}
+
+impl<T: DfuTransport> FaultInjector<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            faults: Vec::new(),
+            transfers: 0,
+            requests: [0; 256],
+            injected: Vec::new(),
+        }
+    }
+
+    /// Inject `fault` when `trigger` is hit.
+    pub fn inject(&mut self, trigger: Trigger, fault: Fault) -> &mut Self {
+        self.faults.push((trigger, fault));
+        self
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Faults fired so far with the control transfer they hit.
+    pub fn injected(&self) -> &[(usize, Fault)] {
+        &self.injected
+    }
+
+    /// Number of control transfers seen so far.
+    pub fn transfers(&self) -> usize {
+        self.transfers
+    }
+
+    fn next_fault(&mut self, request: u8) -> Option<Fault> {
+        let transfer = self.transfers;
+        let nth = self.requests[request as usize];
+        self.transfers += 1;
+        self.requests[request as usize] += 1;
+        let i = self.faults.iter().position(|(trigger, _)| match trigger {
+            Trigger::Transfer(n) => *n == transfer,
+            Trigger::Request(r, n) => *r == request && *n == nth,
+        })?;
+        let (_, fault) = self.faults.remove(i);
+        log::debug!("Injecting {:?} at transfer {}", fault, transfer);
+        self.injected.push((transfer, fault.clone()));
+        Some(fault)
+    }
+}
+
+impl<T: DfuTransport> DfuTransport for FaultInjector<T> {
+    fn interface_number(&self) -> u8 {
+        self.inner.interface_number()
+    }
+
+    async fn control_in(&mut self, request: u8, value: u16, length: u16) -> io::Result<Vec<u8>> {
+        let fault = match self.next_fault(request) {
+            Some(fault) => fault,
+            None => return self.inner.control_in(request, value, length).await,
+        };
+        match fault {
+            Fault::Stall => Err(TransferError::Stall.into()),
+            Fault::BrokenPipe => Err(io::Error::new(io::ErrorKind::BrokenPipe, "injected broken pipe")),
+            Fault::Timeout => {
+                self.inner.control_in(request, value, length).await?;
+                Err(io::Error::new(io::ErrorKind::TimedOut, "injected timeout"))
+            }
+            Fault::ShortStatus(len) => {
+                let mut data = self.inner.control_in(request, value, length).await?;
+                if request == DFU_GET_STATUS {
+                    data.truncate(len);
+                }
+                Ok(data)
+            }
+            Fault::DfuError(status) => {
+                let mut data = self.inner.control_in(request, value, length).await?;
+                if request == DFU_GET_STATUS && data.len() == 6 {
+                    data[0] = status;
+                    data[4] = u8::from(&State::DfuError);
+                }
+                Ok(data)
+            }
+        }
+    }
+
+    async fn control_out(&mut self, request: u8, value: u16, data: &[u8]) -> io::Result<()> {
+        let fault = match self.next_fault(request) {
+            Some(fault) => fault,
+            None => return self.inner.control_out(request, value, data).await,
+        };
+        match fault {
+            Fault::Stall => Err(TransferError::Stall.into()),
+            Fault::BrokenPipe => Err(io::Error::new(io::ErrorKind::BrokenPipe, "injected broken pipe")),
+            Fault::Timeout => {
+                self.inner.control_out(request, value, data).await?;
+                Err(io::Error::new(io::ErrorKind::TimedOut, "injected timeout"))
+            }
+            // status faults only apply to DFU_GETSTATUS
+            Fault::ShortStatus(_) | Fault::DfuError(_) => {
+                self.inner.control_out(request, value, data).await
+            }
+        }
+    }
+
+    fn set_alt_setting(&mut self, alt: u8) -> io::Result<()> {
+        self.inner.set_alt_setting(alt)
+    }
+
+    async fn get_string_descriptor(&mut self, index: u8) -> io::Result<String> {
+        self.inner.get_string_descriptor(index).await
+    }
+
+    fn alt_string_index(&self, alt: u8) -> Option<u8> {
+        self.inner.alt_string_index(alt)
+    }
+
+    fn alt_settings(&self) -> Vec<u8> {
+        self.inner.alt_settings()
+    }
+
+    fn functional_descriptor(&self) -> Option<Vec<u8>> {
+        self.inner.functional_descriptor()
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.inner.reset()
+    }
+
+    async fn reconnect(&mut self) -> io::Result<()> {
+        self.inner.reconnect().await
+    }
+}
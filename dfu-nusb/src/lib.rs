@@ -1,11 +1,35 @@
+pub mod cancel;
 pub mod core;
+pub mod descriptor;
+pub mod device;
 pub mod dfuse_command;
 pub mod error;
+#[cfg(feature = "testing")]
+pub mod fault;
 pub mod memory_layout;
+pub mod option_bytes;
+pub mod otp;
+pub mod progress;
+pub mod runtime;
+#[cfg(feature = "testing")]
+pub mod simulator;
 pub mod status;
+mod timer;
+pub mod trace;
+pub mod transport;
 
-pub use crate::core::Dfu;
+pub use crate::cancel::CancelToken;
+pub use crate::core::{CloseMode, Dfu, Manifestation, Protocol};
+pub use crate::descriptor::{BcdVersion, DfuDescriptor};
+pub use crate::device::{AltSelector, AltSetting, DfuDevice};
 pub use crate::dfuse_command::DfuseCommand;
 pub use crate::error::Error;
-pub use crate::status::{State, Status};
-pub use memory_layout::MemoryLayout;
+#[cfg(feature = "testing")]
+pub use crate::simulator::DfuSimulator;
+pub use crate::status::{State, Status, StatusCode};
+pub use crate::trace::{Recorder, Replay};
+pub use memory_layout::{EraseSector, MemoryLayout};
+pub use crate::option_bytes::OptionBytes;
+pub use crate::otp::OtpImage;
+pub use crate::progress::{Phase, Progress, ProgressObserver};
+pub use crate::transport::{DfuTransport, NusbTransport};
@@ -2,11 +2,88 @@ use crate::error::Error;
 use serde::{Serialize, Serializer};
 use std::fmt;
 use std::str::FromStr;
-#[derive(Debug, Serialize)]
+/// Access allowed to a sector, the DfuSe type letter 'a' to 'g'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Permissions {
+    pub readable: bool,
+    pub erasable: bool,
+    pub writable: bool,
+}
+
+impl Permissions {
+    /// Readable, erasable and writable, the 'g' letter.
+    pub const ALL: Permissions = Permissions {
+        readable: true,
+        erasable: true,
+        writable: true,
+    };
+
+    /// Decode a type letter, 'a' is bit 0 set, 'g' all three bits.
+    pub fn from_letter(letter: char) -> Option<Self> {
+        if !('a'..='g').contains(&letter) {
+            return None;
+        }
+        let bits = letter as u8 - b'a' + 1;
+        Some(Self {
+            readable: bits & 1 != 0,
+            erasable: bits & 2 != 0,
+            writable: bits & 4 != 0,
+        })
+    }
+
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.readable,
+            Access::Erase => self.erasable,
+            Access::Write => self.writable,
+        }
+    }
+
+    pub fn letter(&self) -> char {
+        let bits = self.readable as u8 | (self.erasable as u8) << 1 | (self.writable as u8) << 2;
+        if bits == 0 {
+            return '-';
+        }
+        (b'a' + bits - 1) as char
+    }
+}
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{}",
+            if self.readable { 'r' } else { '-' },
+            if self.erasable { 'e' } else { '-' },
+            if self.writable { 'w' } else { '-' },
+        )
+    }
+}
+
+/// Kind of access checked against sector permissions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Erase,
+    Write,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "readable"),
+            Access::Erase => write!(f, "erasable"),
+            Access::Write => write!(f, "writable"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Page {
     #[serde(serialize_with = "to_hex32_string")]
     pub address: u32,
     pub size: u32,
+    pub permissions: Permissions,
 }
 
 fn to_hex32_string<S>(value: &u32, s: S) -> Result<S::Ok, S::Error>
@@ -16,61 +93,159 @@ where
     s.serialize_str(&format!("0x{:08X}", value))
 }
 
-#[derive(Debug, Serialize)]
+/// Sector erased by one DfuSe erase command, a step of an erase plan.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EraseSector {
+    #[serde(serialize_with = "to_hex32_string")]
+    pub address: u32,
+    pub size: u32,
+}
+
+/// One address group of a DfuSe memory descriptor: a start address and the
+/// sectors following it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Segment {
+    pub name: String,
+    #[serde(serialize_with = "to_hex32_string")]
+    pub start: u32,
+    pub pages: Vec<Page>,
+}
+
+impl Segment {
+    /// Bytes covered by all sectors.
+    pub fn size(&self) -> u32 {
+        self.pages.iter().map(|p| p.size).sum()
+    }
+}
+
+/// Memory layout of a DfuSe alt setting, parsed from its string descriptor.
+///
+/// The grammar is `@name/address/count*size[unit][type],.../address/...`
+/// with the unit ' ' or 'B' for bytes, 'K' or 'M', and the type letter 'a'
+/// to 'g' giving the sector permissions. Sectors without a type letter allow
+/// everything.
+#[derive(Debug, Clone, Default, Serialize)]
 pub struct MemoryLayout {
+    name: String,
+    segments: Vec<Segment>,
     pages: Vec<Page>,
 }
 
+fn parse_address(s: &str) -> Result<u32, Error> {
+    let hex = s.trim();
+    let hex = hex
+        .strip_prefix("0x")
+        .or_else(|| hex.strip_prefix("0X"))
+        .unwrap_or(hex);
+    u32::from_str_radix(hex, 16).map_err(|_| Error::MemoryLayout(format!("Invalid address {}", s)))
+}
+
+/// Parse `count*size[unit][type]` into count, size in bytes and permissions.
+fn parse_sectors(s: &str) -> Result<(u32, u32, Permissions), Error> {
+    let (count, size) = s
+        .split_once('*')
+        .ok_or_else(|| Error::MemoryLayout(format!("Missing '*' in {}", s)))?;
+    let count: u32 = count
+        .trim()
+        .parse()
+        .map_err(|_| Error::MemoryLayout(format!("Invalid sector count {}", s)))?;
+    let size = size.trim_start();
+    let digits = size.find(|c: char| !c.is_ascii_digit()).unwrap_or(size.len());
+    let (number, rest) = size.split_at(digits);
+    let number: u32 = number
+        .parse()
+        .map_err(|_| Error::MemoryLayout(format!("Invalid sector size {}", s)))?;
+    let (multiplier, rest) = match rest.chars().next() {
+        Some('K') => (1024, &rest[1..]),
+        Some('M') => (1024 * 1024, &rest[1..]),
+        Some('B') | Some(' ') => (1, &rest[1..]),
+        _ => (1, rest),
+    };
+    let size = number
+        .checked_mul(multiplier)
+        .ok_or_else(|| Error::MemoryLayout(format!("Sector size too large {}", s)))?;
+    let rest = rest.trim();
+    let mut letters = rest.chars();
+    let permissions = match (letters.next(), letters.next()) {
+        (None, _) => Permissions::ALL,
+        (Some(letter), None) => Permissions::from_letter(letter)
+            .ok_or_else(|| Error::MemoryLayout(format!("Invalid sector type {}", s)))?,
+        _ => return Err(Error::MemoryLayout(format!("Invalid prefix {}", rest))),
+    };
+    Ok((count, size, permissions))
+}
+
 impl FromStr for MemoryLayout {
     type Err = Error;
     fn from_str(s: &str) -> Result<Self, Error> {
-        let s = &s.replace("0x", "");
         let mut sp = s.split('/');
-        let address = sp.nth(1).ok_or_else(|| Error::MemoryLayout(s.into()))?;
-        let mut address =
-            u32::from_str_radix(address, 16).map_err(|_| Error::MemoryLayout(s.into()))?;
+        let name = sp.next().unwrap_or_default();
+        let name = name.strip_prefix('@').unwrap_or(name).trim().to_string();
+        let mut segments = Vec::new();
         let mut pages = Vec::new();
-        for p in sp
-            .next()
-            .ok_or_else(|| Error::MemoryLayout(format!("Missing pages in {}", s)))?
-            .split(',')
-        {
-            let mut keyval = p.split('*');
-            let page_count: u32 = keyval
+        while let Some(address) = sp.next() {
+            // tolerate a trailing '/'
+            if address.trim().is_empty() && !segments.is_empty() {
+                break;
+            }
+            let start = parse_address(address)?;
+            let sectors = sp
                 .next()
-                .ok_or_else(|| Error::MemoryLayout(p.into()))?
-                .parse()
-                .map_err(|_| Error::MemoryLayout(p.into()))?;
-            let valprefix = keyval.next().ok_or_else(|| Error::MemoryLayout(p.into()))?;
-            let size = valprefix.trim_matches(char::is_alphabetic);
-            let prefix = valprefix.trim_matches(char::is_numeric);
-            let mut size: u32 = size.parse().map_err(|_| Error::MemoryLayout(size.into()))?;
-            match &prefix[0..1] {
-                "K" => size *= 1024,
-                "M" => size *= 1024 * 1024,
-                _ => {
-                    return Err(Error::MemoryLayout(format!("Invalid prefix {}", prefix)));
+                .ok_or_else(|| Error::MemoryLayout(format!("Missing pages in {}", s)))?;
+            let mut address = start as u64;
+            let mut segment = Segment {
+                name: name.clone(),
+                start,
+                pages: Vec::new(),
+            };
+            for p in sectors.split(',') {
+                let (count, size, permissions) = parse_sectors(p)?;
+                for _ in 0..count {
+                    if address + size as u64 > 1 << 32 {
+                        return Err(Error::MemoryLayout(format!("Sectors past 4 GiB in {}", s)));
+                    }
+                    segment.pages.push(Page {
+                        address: address as u32,
+                        size,
+                        permissions,
+                    });
+                    address += size as u64;
                 }
             }
-            for _ in 0..page_count {
-                pages.push(Page { address, size });
-                address += size;
-            }
+            pages.extend(segment.pages.iter().cloned());
+            segments.push(segment);
+        }
+        if segments.is_empty() {
+            return Err(Error::MemoryLayout(s.into()));
         }
-        Ok(Self { pages })
+        Ok(Self {
+            name,
+            segments,
+            pages,
+        })
     }
 }
 
 impl fmt::Display for MemoryLayout {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "Show memory layout:")?;
-        writeln!(f)?;
-        for (i, p) in self.pages.iter().enumerate() {
+        writeln!(f, "Show memory layout: {}", self.name)?;
+        let mut i = 0;
+        for segment in &self.segments {
+            writeln!(f)?;
             writeln!(
                 f,
-                "{}: Start: 0x{:08X} Size: {} bytes",
-                i, p.address, p.size
+                "Segment start: 0x{:08X} Size: {} bytes",
+                segment.start,
+                segment.size()
             )?;
+            for p in &segment.pages {
+                writeln!(
+                    f,
+                    "{}: Start: 0x{:08X} Size: {} bytes {}",
+                    i, p.address, p.size, p.permissions
+                )?;
+                i += 1;
+            }
         }
 
         write!(f, "")
@@ -82,31 +257,61 @@ impl MemoryLayout {
         &self.pages
     }
 
+    /// Alt setting name, the part after '@'.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
     /// Return num_pages in region specified
-    pub fn num_pages(&self, mut address: u32, length: u32) -> Result<usize, Error> {
-        let mut pages = 0;
-        let end = address + length;
-        while address < end {
-            let p = self.address(address)?;
-            address += p.size;
-            pages += 1;
+    pub fn num_pages(&self, address: u32, length: u32) -> Result<usize, Error> {
+        Ok(self.erase_plan(address, length)?.len())
+    }
+
+    /// Sectors touched by the region, each with its own start and size, in
+    /// address order. Fails if part of the region is not described.
+    pub fn erase_plan(&self, address: u32, length: u32) -> Result<Vec<EraseSector>, Error> {
+        let mut plan = Vec::new();
+        let end = address as u64 + length as u64;
+        let mut next = address as u64;
+        while next < end {
+            let p = self.address(next as u32)?;
+            plan.push(EraseSector {
+                address: p.address,
+                size: p.size,
+            });
+            next = p.address as u64 + p.size as u64;
         }
-        Ok(pages)
+        Ok(plan)
+    }
+
+    /// Check that every sector overlapping the region allows `access`.
+    /// Addresses the layout does not describe are left to the device.
+    pub fn check(&self, address: u32, length: u32, access: Access) -> Result<(), Error> {
+        let end = address as u64 + length as u64;
+        for p in &self.pages {
+            let overlaps = (p.address as u64) < end && (address as u64) < p.address as u64 + p.size as u64;
+            if overlaps && !p.permissions.allows(access) {
+                return Err(Error::Permission(p.clone(), access));
+            }
+        }
+        Ok(())
     }
 
     pub fn address(&self, address: u32) -> Result<Page, Error> {
         for p in &self.pages {
-            if address >= p.address && address < p.address + p.size {
-                return Ok(Page {
-                    address: p.address,
-                    size: p.size,
-                });
+            if address >= p.address && (address as u64) < p.address as u64 + p.size as u64 {
+                return Ok(p.clone());
             }
         }
         Err(Error::Address(address))
     }
 }
 
+#[allow(clippy::bool_assert_comparison, clippy::iter_nth, clippy::iter_nth_zero)]
 mod tests {
     #[test]
     fn test_memory_address() {
@@ -157,6 +362,28 @@ mod tests {
 
         let n = m.num_pages(0x0801_4000, 0x8000).unwrap();
         assert_eq!(2, n);
+
+        // unaligned start still reaches the next page
+        let n = m.num_pages(0x0801_2000, 0x4000).unwrap();
+        assert_eq!(2, n);
+    }
+    #[test]
+    fn test_memory_erase_plan() {
+        use super::{EraseSector, MemoryLayout};
+        use std::str::FromStr;
+        let m = MemoryLayout::from_str("@Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg").unwrap();
+        let sector = |address, size| EraseSector { address, size };
+        assert_eq!(
+            vec![
+                sector(0x0800_C000, 0x4000),
+                sector(0x0801_0000, 0x10000),
+                sector(0x0802_0000, 0x20000),
+            ],
+            m.erase_plan(0x0800_C100, 0x14000).unwrap()
+        );
+        assert_eq!(vec![sector(0x080E_0000, 0x20000)], m.erase_plan(0x080F_FFFF, 1).unwrap());
+        assert!(m.erase_plan(0x0800_0000, 0).unwrap().is_empty());
+        assert!(m.erase_plan(0x080F_FFFF, 2).is_err());
     }
     #[test]
     fn test_memory_from() {
@@ -183,4 +410,83 @@ mod tests {
         assert_eq!(16384, p.iter().nth(1).unwrap().size);
         assert_eq!(65536, p.iter().nth(2).unwrap().size);
     }
+    #[test]
+    fn test_memory_dfuse_grammar() {
+        use super::{MemoryLayout, Permissions};
+        use std::str::FromStr;
+        // STM32F4 system bootloader
+        let m = MemoryLayout::from_str("@Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg").unwrap();
+        assert_eq!("Internal Flash", m.name());
+        assert_eq!(1, m.segments().len());
+        assert_eq!(12, m.pages().len());
+        assert_eq!(0x0800_0000, m.segments()[0].start);
+        assert_eq!(1024 * 1024, m.segments()[0].size());
+        assert_eq!(0x0801_0000, m.pages()[4].address);
+        assert_eq!(0x10000, m.pages()[4].size);
+        assert_eq!(0x0802_0000, m.pages()[5].address);
+        assert_eq!(Permissions::ALL, m.address(0x080F_FFFF).unwrap().permissions);
+
+        // bytes with ' ' and 'B', several address groups
+        let m = MemoryLayout::from_str(
+            "@Option Bytes  /0x1FFFC000/01*016 e/0x1FFEC000/01*016Be/0x1FFF7800/01*512 a",
+        )
+        .unwrap();
+        assert_eq!("Option Bytes", m.name());
+        assert_eq!(3, m.segments().len());
+        assert_eq!("Option Bytes", m.segments()[2].name);
+        let p = m.address(0x1FFE_C00F).unwrap();
+        assert_eq!(0x1FFE_C000, p.address);
+        assert_eq!(16, p.size);
+        let e = Permissions {
+            readable: true,
+            erasable: false,
+            writable: true,
+        };
+        assert_eq!(e, p.permissions);
+        assert_eq!('e', p.permissions.letter());
+        assert_eq!("r-w", p.permissions.to_string());
+        assert_eq!(512, m.address(0x1FFF_7800).unwrap().size);
+        assert!(m.address(0x1FFF_7A00).is_err());
+
+        for letter in 'a'..='g' {
+            assert_eq!(letter, Permissions::from_letter(letter).unwrap().letter());
+        }
+        assert!(Permissions::from_letter('h').is_none());
+
+        assert!(MemoryLayout::from_str("@Flash/0x08000000/04*016Kz").is_err());
+
+        // the last sector may end right at 4 GiB
+        let m = MemoryLayout::from_str("@Flash/0xFFFFF000/01*4Kg").unwrap();
+        assert_eq!(0xFFFF_F000, m.address(0xFFFF_FFFF).unwrap().address);
+        assert!(m.address(0xFFFF_EFFF).is_err());
+    }
+    #[test]
+    fn test_memory_check() {
+        use super::{Access, MemoryLayout};
+        use crate::Error;
+        use std::str::FromStr;
+        // 0x0800_0000 read only, 0x0800_0800 erase and write only
+        let m = MemoryLayout::from_str("@Flash/0x08000000/01*2Ka,01*2Kf,02*2Kg").unwrap();
+        assert!(m.check(0x0800_0000, 0x800, Access::Read).is_ok());
+        assert!(m.check(0x0800_0800, 0x1800, Access::Write).is_ok());
+        assert!(m.check(0x0800_1000, 0x1000, Access::Read).is_ok());
+        match m.check(0x0800_07FF, 2, Access::Write) {
+            Err(Error::Permission(p, Access::Write)) => assert_eq!(0x0800_0000, p.address),
+            res => panic!("unexpected {:?}", res),
+        }
+        match m.check(0x0800_0000, 0x2000, Access::Read) {
+            Err(Error::Permission(p, Access::Read)) => assert_eq!(0x0800_0800, p.address),
+            res => panic!("unexpected {:?}", res),
+        }
+        assert!(m.check(0x0800_0000, 0, Access::Erase).is_ok());
+        // not described, left to the device
+        assert!(m.check(0x0900_0000, 0x1000, Access::Erase).is_ok());
+        assert!(MemoryLayout::from_str("@Flash/0x08000000/04*016Kg/0x08100000").is_err());
+        assert!(MemoryLayout::from_str("@Flash/0xFFFFF000/02*4Kg").is_err());
+        assert!(MemoryLayout::from_str("@Flash").is_err());
+
+        let m = MemoryLayout::from_str("@Flash/0xFFFFE000/01*4Ka,01*4Kg").unwrap();
+        assert!(m.check(0xFFFF_F000, 0x1000, Access::Write).is_ok());
+        assert!(m.check(0xFFFF_EFFF, 2, Access::Write).is_err());
+    }
 }
@@ -0,0 +1,585 @@
+use crate::core::Dfu;
+use crate::error::Error;
+use crate::memory_layout::Access;
+use crate::progress::Phase;
+use crate::transport::DfuTransport;
+use std::fmt;
+use std::str::FromStr;
+
+/// Name of the DfuSe alt setting holding the option bytes.
+pub const OPTION_BYTES_ALT: &str = "Option Bytes";
+
+/// Read protection keys.
+const RDP_LEVEL0: u8 = 0xAA;
+/// STM32F1 uses its own key for level 0, only valid in the 0x1FFFF800 block.
+const RDP_LEVEL0_F1: u8 = 0xA5;
+const RDP_LEVEL2: u8 = 0xCC;
+
+const USER_WDG_SW: u8 = 1 << 0;
+const USER_NRST_STOP: u8 = 1 << 1;
+const USER_NRST_STDBY: u8 = 1 << 2;
+const USER_NBOOT0: u8 = 1 << 3;
+const USER_NBOOT1: u8 = 1 << 4;
+
+const F4_USER_BOR_SHIFT: u8 = 2;
+const F4_USER_BOR_MASK: u8 = 0x03 << F4_USER_BOR_SHIFT;
+const F4_USER_WDG_SW: u8 = 1 << 5;
+const F4_USER_NRST_STOP: u8 = 1 << 6;
+const F4_USER_NRST_STDBY: u8 = 1 << 7;
+/// Sectors covered by nWRP on STM32F2/F4.
+const F4_WRP_SECTORS: u32 = 12;
+
+/// How a family lays out its option block, told apart by the block address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OptionLayout {
+    /// STM32F0/F1/F3 at 0x1FFFF800: RDP, USER, DATA0, DATA1 and WRP0-3, each
+    /// byte followed by its complement.
+    ComplementPairs,
+    /// STM32F2/F4 at 0x1FFFC000: USER with BOR_LEV, RDP and nWRP.
+    F2F4,
+}
+
+impl OptionLayout {
+    pub fn from_address(address: u32) -> Option<Self> {
+        match address {
+            0x1FFF_F800 => Some(OptionLayout::ComplementPairs),
+            0x1FFF_C000 => Some(OptionLayout::F2F4),
+            _ => None,
+        }
+    }
+
+    /// Size of the option block in bytes.
+    pub fn size(&self) -> usize {
+        16
+    }
+}
+
+/// Part behind the 0x1FFFF800 option block, which the block does not tell
+/// apart but which decides whether USER bits 3 and 4 are nBOOT0 and nBOOT1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Family {
+    /// STM32F0 other than F04x and F09x, nBOOT1 only.
+    F0,
+    /// STM32F04x, nBOOT0 and nBOOT1.
+    F04x,
+    /// STM32F09x, nBOOT0 and nBOOT1.
+    F09x,
+    /// STM32F1, no nBOOT bits, bit 3 is BFB2 on XL-density parts.
+    F1,
+    /// STM32F3, nBOOT1 only.
+    F3,
+}
+
+impl Family {
+    fn has_nboot0(&self) -> bool {
+        matches!(self, Family::F04x | Family::F09x)
+    }
+
+    fn has_nboot1(&self) -> bool {
+        !matches!(self, Family::F1)
+    }
+}
+
+impl FromStr for Family {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "f0" => Ok(Family::F0),
+            "f04x" => Ok(Family::F04x),
+            "f09x" => Ok(Family::F09x),
+            "f1" => Ok(Family::F1),
+            "f3" => Ok(Family::F3),
+            _ => Err(Error::Argument(format!(
+                "Unknown family {}, expect f0, f04x, f09x, f1 or f3",
+                s
+            ))),
+        }
+    }
+}
+
+/// Brown out reset threshold of STM32F2/F4. BOR_LEV counts down, 0b11 turns
+/// the brown out reset off and 0b00 is the highest threshold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BorLevel {
+    Off,
+    Level1,
+    Level2,
+    Level3,
+}
+
+impl BorLevel {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0x03 {
+            0b00 => BorLevel::Level3,
+            0b01 => BorLevel::Level2,
+            0b10 => BorLevel::Level1,
+            _ => BorLevel::Off,
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        match self {
+            BorLevel::Level3 => 0b00,
+            BorLevel::Level2 => 0b01,
+            BorLevel::Level1 => 0b10,
+            BorLevel::Off => 0b11,
+        }
+    }
+}
+
+impl FromStr for BorLevel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" | "0" => Ok(BorLevel::Off),
+            "1" => Ok(BorLevel::Level1),
+            "2" => Ok(BorLevel::Level2),
+            "3" => Ok(BorLevel::Level3),
+            _ => Err(Error::Argument(format!("Invalid BOR level {}, expect off, 1, 2 or 3", s))),
+        }
+    }
+}
+
+impl fmt::Display for BorLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BorLevel::Off => write!(f, "Off"),
+            BorLevel::Level1 => write!(f, "Level 1"),
+            BorLevel::Level2 => write!(f, "Level 2"),
+            BorLevel::Level3 => write!(f, "Level 3"),
+        }
+    }
+}
+
+/// Read protection level decoded from the RDP byte.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RdpLevel {
+    /// No protection, RDP is 0xAA (0xA5 on STM32F1).
+    Level0,
+    /// Flash can't be read over the debug port or the bootloader.
+    Level1,
+    /// Permanent, the bootloader and debug port are gone for good.
+    Level2,
+}
+
+impl fmt::Display for RdpLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RdpLevel::Level0 => write!(f, "Level 0 (no protection)"),
+            RdpLevel::Level1 => write!(f, "Level 1 (read protected)"),
+            RdpLevel::Level2 => write!(f, "Level 2 (chip protected)"),
+        }
+    }
+}
+
+/// Decoded STM32 option bytes.
+///
+/// Bits not decoded here are kept from the block they were read from when
+/// written back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptionBytes {
+    pub layout: OptionLayout,
+    pub address: u32,
+    /// Raw RDP byte.
+    pub rdp: u8,
+    /// Watchdog started by software rather than at reset.
+    pub iwdg_sw: bool,
+    /// No reset when entering Stop mode.
+    pub nrst_stop: bool,
+    /// No reset when entering Standby mode.
+    pub nrst_stdby: bool,
+    /// Brown out reset level, STM32F2/F4 only.
+    pub bor_level: Option<BorLevel>,
+    /// nBOOT0, only decoded once `with_family` names a part that has it.
+    pub nboot0: Option<bool>,
+    /// nBOOT1, only decoded once `with_family` names a part that has it.
+    pub nboot1: Option<bool>,
+    /// Write protected sectors or page groups, bit n set protects n.
+    pub wrp: u32,
+    raw: Vec<u8>,
+}
+
+impl OptionBytes {
+    /// Decode an option block read from `address`, checking complement pairs
+    /// where the family has them.
+    pub fn decode(address: u32, raw: &[u8]) -> Result<Self, Error> {
+        let layout = OptionLayout::from_address(address).ok_or_else(|| {
+            Error::OptionBytes(format!("Unknown option byte layout at 0x{:08X}", address))
+        })?;
+        if raw.len() < layout.size() {
+            return Err(Error::OptionBytes(format!(
+                "Option block is {} bytes, expected {}",
+                raw.len(),
+                layout.size()
+            )));
+        }
+        let raw = raw[..layout.size()].to_vec();
+        match layout {
+            OptionLayout::ComplementPairs => {
+                for (i, pair) in raw.chunks(2).enumerate() {
+                    if pair[0] != !pair[1] {
+                        return Err(Error::OptionBytes(format!(
+                            "Option byte 0x{:08X} is 0x{:02X} but its complement is 0x{:02X}",
+                            address + 2 * i as u32,
+                            pair[0],
+                            pair[1]
+                        )));
+                    }
+                }
+                let user = raw[2];
+                Ok(Self {
+                    layout,
+                    address,
+                    rdp: raw[0],
+                    iwdg_sw: user & USER_WDG_SW != 0,
+                    nrst_stop: user & USER_NRST_STOP != 0,
+                    nrst_stdby: user & USER_NRST_STDBY != 0,
+                    bor_level: None,
+                    nboot0: None,
+                    nboot1: None,
+                    // WRP bits are active low
+                    wrp: !u32::from_le_bytes([raw[8], raw[10], raw[12], raw[14]]),
+                    raw,
+                })
+            }
+            OptionLayout::F2F4 => {
+                let user = raw[0];
+                let nwrp = u16::from_le_bytes([raw[8], raw[9]]) as u32;
+                Ok(Self {
+                    layout,
+                    address,
+                    rdp: raw[1],
+                    iwdg_sw: user & F4_USER_WDG_SW != 0,
+                    nrst_stop: user & F4_USER_NRST_STOP != 0,
+                    nrst_stdby: user & F4_USER_NRST_STDBY != 0,
+                    bor_level: Some(BorLevel::from_bits((user & F4_USER_BOR_MASK) >> F4_USER_BOR_SHIFT)),
+                    nboot0: None,
+                    nboot1: None,
+                    wrp: !nwrp & ((1 << F4_WRP_SECTORS) - 1),
+                    raw,
+                })
+            }
+        }
+    }
+
+    /// Decode the nBOOT bits of a 0x1FFFF800 block for the part it was
+    /// read from.
+    pub fn with_family(mut self, family: Family) -> Result<Self, Error> {
+        if self.layout != OptionLayout::ComplementPairs {
+            return Err(Error::OptionBytes(format!(
+                "{:?} does not use the {:?} layout",
+                family, self.layout
+            )));
+        }
+        let user = self.raw[2];
+        self.nboot0 = family.has_nboot0().then_some(user & USER_NBOOT0 != 0);
+        self.nboot1 = family.has_nboot1().then_some(user & USER_NBOOT1 != 0);
+        Ok(self)
+    }
+
+    pub fn rdp_level(&self) -> RdpLevel {
+        match (self.layout, self.rdp) {
+            (_, RDP_LEVEL0) | (OptionLayout::ComplementPairs, RDP_LEVEL0_F1) => RdpLevel::Level0,
+            (_, RDP_LEVEL2) => RdpLevel::Level2,
+            _ => RdpLevel::Level1,
+        }
+    }
+
+    /// Block as read, before any change.
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// Block to write back: the block read with the decoded fields applied
+    /// and complements recomputed.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut raw = self.raw.clone();
+        let set = |byte: &mut u8, mask: u8, on: bool| {
+            if on {
+                *byte |= mask;
+            } else {
+                *byte &= !mask;
+            }
+        };
+        match self.layout {
+            OptionLayout::ComplementPairs => {
+                raw[0] = self.rdp;
+                let user = &mut raw[2];
+                set(user, USER_WDG_SW, self.iwdg_sw);
+                set(user, USER_NRST_STOP, self.nrst_stop);
+                set(user, USER_NRST_STDBY, self.nrst_stdby);
+                if let Some(nboot0) = self.nboot0 {
+                    set(user, USER_NBOOT0, nboot0);
+                }
+                if let Some(nboot1) = self.nboot1 {
+                    set(user, USER_NBOOT1, nboot1);
+                }
+                let wrp = (!self.wrp).to_le_bytes();
+                for (i, b) in wrp.iter().enumerate() {
+                    raw[8 + 2 * i] = *b;
+                }
+                for i in (0..raw.len()).step_by(2) {
+                    raw[i + 1] = !raw[i];
+                }
+            }
+            OptionLayout::F2F4 => {
+                raw[1] = self.rdp;
+                let user = &mut raw[0];
+                set(user, F4_USER_WDG_SW, self.iwdg_sw);
+                set(user, F4_USER_NRST_STOP, self.nrst_stop);
+                set(user, F4_USER_NRST_STDBY, self.nrst_stdby);
+                if let Some(bor) = self.bor_level {
+                    *user = (*user & !F4_USER_BOR_MASK) | (bor.bits() << F4_USER_BOR_SHIFT);
+                }
+                let mask = (1 << F4_WRP_SECTORS) - 1;
+                let nwrp = u16::from_le_bytes([raw[8], raw[9]]) as u32;
+                let nwrp = (nwrp & !mask) | (!self.wrp & mask);
+                raw[8..10].copy_from_slice(&(nwrp as u16).to_le_bytes());
+            }
+        }
+        raw
+    }
+
+    /// Change one field from its CLI name, e.g. `("bor", "off")` or
+    /// `("wrp", "0x0F")`. Lowering read protection or going to level 2 is
+    /// refused, both erase or brick the part.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        let number = || {
+            let v = value.trim();
+            match v.strip_prefix("0x").or_else(|| v.strip_prefix("0X")) {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => v.parse(),
+            }
+            .map_err(|_| Error::Argument(format!("Invalid value {} for {}", value, key)))
+        };
+        let flag = || match number()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::Argument(format!("{} takes 0 or 1", key))),
+        };
+        match key {
+            "rdp" => match (self.rdp_level(), number()?) {
+                (RdpLevel::Level0, 0) | (RdpLevel::Level1, 1) => {}
+                (RdpLevel::Level0, 1) => self.rdp = 0x00,
+                (_, 2) => {
+                    return Err(Error::Argument(
+                        "RDP level 2 is permanent, refusing to set it".into(),
+                    ))
+                }
+                (RdpLevel::Level1, 0) => {
+                    return Err(Error::Argument(
+                        "Lowering RDP mass erases the flash, use read-unprotect".into(),
+                    ))
+                }
+                _ => return Err(Error::Argument(format!("Invalid RDP level {}", value))),
+            },
+            "iwdg_sw" => self.iwdg_sw = flag()?,
+            "nrst_stop" => self.nrst_stop = flag()?,
+            "nrst_stdby" => self.nrst_stdby = flag()?,
+            "bor" => match self.bor_level {
+                Some(_) => self.bor_level = Some(value.parse()?),
+                None => return Err(Error::Argument("No BOR level on this part".into())),
+            },
+            "nboot0" | "nboot1" => {
+                let bit = if key == "nboot0" { &mut self.nboot0 } else { &mut self.nboot1 };
+                if bit.is_none() {
+                    return Err(Error::Argument(format!(
+                        "No {} on this part, or its family is not known",
+                        key
+                    )));
+                }
+                *bit = Some(flag()?);
+            }
+            "wrp" => {
+                let wrp = number()?;
+                if self.layout == OptionLayout::F2F4 && wrp >> F4_WRP_SECTORS != 0 {
+                    return Err(Error::Argument(format!("Only {} sectors", F4_WRP_SECTORS)));
+                }
+                self.wrp = wrp;
+            }
+            _ => return Err(Error::Argument(format!("Unknown option {}", key))),
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for OptionBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Option bytes at 0x{:08X} ({:?})", self.address, self.layout)?;
+        writeln!(f, "RDP: 0x{:02X} {}", self.rdp, self.rdp_level())?;
+        if let Some(bor) = self.bor_level {
+            writeln!(f, "BOR level: {}", bor)?;
+        }
+        writeln!(f, "IWDG_SW: {}", self.iwdg_sw as u8)?;
+        writeln!(f, "nRST_STOP: {}", self.nrst_stop as u8)?;
+        writeln!(f, "nRST_STDBY: {}", self.nrst_stdby as u8)?;
+        if let Some(nboot0) = self.nboot0 {
+            writeln!(f, "nBOOT0: {}", nboot0 as u8)?;
+        }
+        if let Some(nboot1) = self.nboot1 {
+            writeln!(f, "nBOOT1: {}", nboot1 as u8)?;
+        }
+        write!(f, "WRP: 0x{:08X}", self.wrp)
+    }
+}
+
+impl<T: DfuTransport> Dfu<T> {
+    /// Read and decode the option bytes from the "Option Bytes" alt
+    /// setting, returning to the current alt setting afterwards.
+    pub async fn read_option_bytes(&mut self) -> Result<OptionBytes, Error> {
+        let alt = self.alt();
+        self.select_alt(OPTION_BYTES_ALT).await?;
+        let res = self.read_option_block().await;
+        self.select_alt(alt).await?;
+        res
+    }
+
+    async fn read_option_block(&mut self) -> Result<OptionBytes, Error> {
+        let address = self
+            .memory_layout()
+            .segments()
+            .first()
+            .map(|s| s.start)
+            .ok_or_else(|| Error::OptionBytes("Option Bytes alt setting is empty".into()))?;
+        let layout = OptionLayout::from_address(address).ok_or_else(|| {
+            Error::OptionBytes(format!("Unknown option byte layout at 0x{:08X}", address))
+        })?;
+        self.memory_layout().check(address, layout.size() as u32, Access::Read)?;
+        let mut raw = Vec::with_capacity(layout.size());
+        self.dfuse_read(address, layout.size() as u32, Phase::Read, |_, v| {
+            raw.extend(v);
+            Ok(())
+        })
+        .await?;
+        OptionBytes::decode(address, &raw)
+    }
+
+    /// Read-modify-write of the option bytes: the block is read again and
+    /// must still decode to the block `options` came from before the new
+    /// one is written and read back.
+    /// STM32 bootloaders apply new option bytes with a reset once the DFU
+    /// session ends.
+    pub async fn write_option_bytes(&mut self, options: &OptionBytes) -> Result<(), Error> {
+        let alt = self.alt();
+        self.select_alt(OPTION_BYTES_ALT).await?;
+        let res = self.write_option_block(options).await;
+        self.select_alt(alt).await?;
+        res
+    }
+
+    async fn write_option_block(&mut self, options: &OptionBytes) -> Result<(), Error> {
+        let current = self.read_option_block().await?;
+        if current.raw != options.raw {
+            return Err(Error::OptionBytes(
+                "Option bytes changed since they were read".into(),
+            ));
+        }
+        let raw = options.encode();
+        OptionBytes::decode(options.address, &raw)?;
+        self.dfuse_write(options.address, &raw).await?;
+        let written = self.read_option_block().await?;
+        if written.raw != raw {
+            return Err(Error::OptionBytes("Option bytes read back differ".into()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_option_bytes_complement_pairs() {
+        use super::{Family, OptionBytes, OptionLayout, RdpLevel};
+        // STM32F0 defaults: level 0, USER 0xFF, no write protection
+        let raw = [
+            0xAA, 0x55, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00,
+        ];
+        let ob = OptionBytes::decode(0x1FFF_F800, &raw).unwrap();
+        assert_eq!(OptionLayout::ComplementPairs, ob.layout);
+        assert_eq!(RdpLevel::Level0, ob.rdp_level());
+        assert!(ob.iwdg_sw && ob.nrst_stop && ob.nrst_stdby);
+        // the block alone doesn't say which part it is
+        assert_eq!(None, ob.nboot1);
+        assert!(ob.clone().set("nboot1", "0").is_err());
+        let mut ob = ob.with_family(Family::F0).unwrap();
+        assert_eq!(None, ob.nboot0);
+        assert_eq!(Some(true), ob.nboot1);
+        assert_eq!(0, ob.wrp);
+        assert_eq!(raw.to_vec(), ob.encode());
+
+        ob.set("nboot1", "0").unwrap();
+        ob.set("wrp", "0x3").unwrap();
+        ob.set("rdp", "1").unwrap();
+        let raw = ob.encode();
+        assert_eq!(&[0x00, 0xFF, 0xEF, 0x10], &raw[0..4]);
+        assert_eq!(&[0xFC, 0x03, 0xFF, 0x00], &raw[8..12]);
+        let ob = OptionBytes::decode(0x1FFF_F800, &raw).unwrap();
+        assert_eq!(RdpLevel::Level1, ob.rdp_level());
+        let mut f1 = ob.clone();
+        f1.rdp = 0xA5;
+        assert_eq!(RdpLevel::Level0, f1.rdp_level());
+        assert_eq!(Some(false), ob.clone().with_family(Family::F3).unwrap().nboot1);
+        assert_eq!(Some(true), ob.clone().with_family(Family::F09x).unwrap().nboot0);
+        assert_eq!(3, ob.wrp);
+
+        // BFB2 in bit 3 of an XL-density STM32F1 is neither read nor written
+        let mut f1 = ob.clone().with_family(Family::F1).unwrap();
+        assert_eq!((None, None), (f1.nboot0, f1.nboot1));
+        assert!(f1.set("nboot0", "0").is_err());
+        f1.set("nrst_stdby", "0").unwrap();
+        assert_eq!(0xEB, f1.encode()[2]);
+
+        let mut broken = raw.clone();
+        broken[3] = 0x11;
+        assert!(OptionBytes::decode(0x1FFF_F800, &broken).is_err());
+        assert!(OptionBytes::decode(0x0800_0000, &raw).is_err());
+    }
+
+    #[test]
+    fn test_option_bytes_f4() {
+        use super::{BorLevel, OptionBytes, OptionLayout, RdpLevel};
+        // BOR_LEV 0b11, brown out reset off
+        let mut raw = vec![0xFF; 16];
+        raw[0] = 0xEC;
+        raw[1] = 0xAA;
+        raw[8] = 0xFF;
+        raw[9] = 0x0F;
+        let mut ob = OptionBytes::decode(0x1FFF_C000, &raw).unwrap();
+        assert_eq!(OptionLayout::F2F4, ob.layout);
+        assert_eq!(Some(BorLevel::Off), ob.bor_level);
+        assert!(ob.iwdg_sw && ob.nrst_stop && ob.nrst_stdby);
+        assert_eq!(None, ob.nboot0);
+        assert_eq!(0, ob.wrp);
+        assert_eq!(raw, ob.encode());
+
+        ob.set("bor", "1").unwrap();
+        ob.set("iwdg_sw", "0").unwrap();
+        ob.set("wrp", "0x801").unwrap();
+        assert!(ob.set("wrp", "0x1000").is_err());
+        assert!(ob.set("bor", "4").is_err());
+        assert!(ob.set("nboot1", "0").is_err());
+        assert!(ob.set("rdp", "2").is_err());
+        assert!(ob.clone().with_family(super::Family::F1).is_err());
+        let raw = ob.encode();
+        assert_eq!(0xC8, raw[0]);
+        assert_eq!(&[0xFE, 0x07], &raw[8..10]);
+        let mut ob = OptionBytes::decode(0x1FFF_C000, &raw).unwrap();
+        assert_eq!(Some(BorLevel::Level1), ob.bor_level);
+        assert_eq!(0x801, ob.wrp);
+        assert_eq!(RdpLevel::Level0, ob.rdp_level());
+
+        // BOR_LEV 0b00 is the highest threshold
+        ob.set("bor", "3").unwrap();
+        assert_eq!(0xC0, ob.encode()[0]);
+        ob.set("bor", "off").unwrap();
+        assert_eq!(0xCC, ob.encode()[0]);
+
+        // 0xA5 is level 0 only in the STM32F0/F1/F3 block
+        ob.rdp = 0xA5;
+        assert_eq!(RdpLevel::Level1, ob.rdp_level());
+        ob.rdp = 0x00;
+        assert!(ob.set("rdp", "0").is_err());
+    }
+}
@@ -0,0 +1,279 @@
+use crate::core::Dfu;
+use crate::error::Error;
+use crate::memory_layout::{Access, MemoryLayout};
+use crate::progress::Phase;
+use crate::transport::DfuTransport;
+use std::fmt;
+
+/// Name of the DfuSe alt setting holding the one-time programmable area.
+pub const OTP_ALT: &str = "OTP Memory";
+
+/// Value of an OTP byte that was never programmed.
+const UNPROGRAMMED: u8 = 0xFF;
+
+/// Data and lock areas of an OTP alt setting such as
+/// "@OTP Memory /0x1FFF7800/01*512 e,01*016 e": the data sector is split in
+/// as many blocks as the lock sector has bytes, and programming a lock byte
+/// locks its block. This is the STM32F2/F4/F7 OTP, programmed byte by byte
+/// without ECC.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OtpLayout {
+    pub data_start: u32,
+    pub data_size: u32,
+    pub lock_start: u32,
+    pub blocks: u32,
+}
+
+impl OtpLayout {
+    pub fn from_layout(layout: &MemoryLayout) -> Result<Self, Error> {
+        match layout.pages().as_slice() {
+            [data, lock] if lock.size > 0 && data.size % lock.size == 0 => Ok(Self {
+                data_start: data.address,
+                data_size: data.size,
+                lock_start: lock.address,
+                blocks: lock.size,
+            }),
+            _ => Err(Error::Otp(format!(
+                "Expect a data and a lock sector in '{}'",
+                layout.name()
+            ))),
+        }
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.data_size / self.blocks
+    }
+}
+
+/// Contents of the OTP area.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OtpImage {
+    pub layout: OtpLayout,
+    pub data: Vec<u8>,
+    pub lock: Vec<u8>,
+}
+
+impl OtpImage {
+    pub fn byte(&self, address: u32) -> Option<u8> {
+        let l = &self.layout;
+        if let Some(offset) = address.checked_sub(l.data_start).filter(|o| *o < l.data_size) {
+            return self.data.get(offset as usize).copied();
+        }
+        let offset = address.checked_sub(l.lock_start).filter(|o| *o < l.blocks)?;
+        self.lock.get(offset as usize).copied()
+    }
+
+    fn byte_mut(&mut self, address: u32) -> Option<&mut u8> {
+        let l = self.layout;
+        if let Some(offset) = address.checked_sub(l.data_start).filter(|o| *o < l.data_size) {
+            return self.data.get_mut(offset as usize);
+        }
+        let offset = address.checked_sub(l.lock_start).filter(|o| *o < l.blocks)?;
+        self.lock.get_mut(offset as usize)
+    }
+
+    pub fn is_locked(&self, block: u32) -> bool {
+        self.lock.get(block as usize).is_some_and(|b| *b != UNPROGRAMMED)
+    }
+
+    /// The image after writing `buf` at `address`.
+    ///
+    /// Refuses to change a byte that is already programmed or that lies in
+    /// a locked block; writing the value a byte already holds is fine.
+    pub fn apply(&self, address: u32, buf: &[u8]) -> Result<Self, Error> {
+        let mut after = self.clone();
+        for (i, new) in buf.iter().enumerate() {
+            let address = byte_address(address, i)?;
+            let old = after
+                .byte_mut(address)
+                .ok_or_else(|| Error::Otp(format!("0x{:08X} is outside the OTP area", address)))?;
+            if *old == *new {
+                continue;
+            }
+            if *old != UNPROGRAMMED {
+                return Err(Error::Otp(format!(
+                    "0x{:08X} is already programmed to 0x{:02X}",
+                    address, old
+                )));
+            }
+            *old = *new;
+            let l = &self.layout;
+            if let Some(offset) = address.checked_sub(l.data_start).filter(|o| *o < l.data_size) {
+                let block = offset / l.block_size();
+                if self.is_locked(block) {
+                    return Err(Error::Otp(format!("Block {} is locked", block)));
+                }
+            }
+        }
+        Ok(after)
+    }
+
+    /// Runs of `buf` written at `address` that differ from the image, the
+    /// only bytes that need programming.
+    pub fn changes<'a>(&self, address: u32, buf: &'a [u8]) -> Result<Vec<(u32, &'a [u8])>, Error> {
+        let mut runs = Vec::new();
+        let mut start = None;
+        for (i, new) in buf.iter().enumerate() {
+            let changed = self.byte(byte_address(address, i)?) != Some(*new);
+            match (changed, start) {
+                (true, None) => start = Some(i),
+                (false, Some(s)) => {
+                    runs.push((address + s as u32, &buf[s..i]));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(s) = start {
+            runs.push((address + s as u32, &buf[s..]));
+        }
+        Ok(runs)
+    }
+}
+
+/// Address of byte `i` of a buffer written at `address`.
+fn byte_address(address: u32, i: usize) -> Result<u32, Error> {
+    u32::try_from(i)
+        .ok()
+        .and_then(|i| address.checked_add(i))
+        .ok_or_else(|| Error::Otp(format!("Data at 0x{:08X} runs past the 4 GiB address space", address)))
+}
+
+impl fmt::Display for OtpImage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let size = self.layout.block_size() as usize;
+        for (block, data) in self.data.chunks(size).enumerate() {
+            let address = self.layout.data_start + (block * size) as u32;
+            let lock = if self.is_locked(block as u32) { "locked" } else { "open" };
+            write!(f, "{:2} 0x{:08X} {:6}", block, address, lock)?;
+            for b in data {
+                write!(f, " {:02X}", b)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: DfuTransport> Dfu<T> {
+    /// Read the data and lock areas from the "OTP Memory" alt setting,
+    /// returning to the current alt setting afterwards.
+    pub async fn read_otp(&mut self) -> Result<OtpImage, Error> {
+        let alt = self.alt();
+        self.select_alt(OTP_ALT).await?;
+        let res = self.read_otp_area().await;
+        self.select_alt(alt).await?;
+        res
+    }
+
+    async fn read_otp_area(&mut self) -> Result<OtpImage, Error> {
+        let layout = OtpLayout::from_layout(self.memory_layout())?;
+        let data = self.read_otp_range(layout.data_start, layout.data_size).await?;
+        let lock = self.read_otp_range(layout.lock_start, layout.blocks).await?;
+        Ok(OtpImage { layout, data, lock })
+    }
+
+    async fn read_otp_range(&mut self, address: u32, length: u32) -> Result<Vec<u8>, Error> {
+        self.memory_layout().check(address, length, Access::Read)?;
+        let mut v = Vec::with_capacity(length as usize);
+        self.dfuse_read(address, length, Phase::Read, |_, chunk| {
+            v.extend(chunk);
+            Ok(())
+        })
+        .await?;
+        Ok(v)
+    }
+
+    /// Program `buf` into OTP at `address`, without erasing.
+    ///
+    /// Nothing is written if a byte that is already programmed would change
+    /// or the data lies in a locked block, and only the bytes that change are
+    /// programmed. The whole area is read back
+    /// afterwards, so lock bytes that changed unasked are reported as well.
+    pub async fn write_otp(&mut self, address: u32, buf: &[u8]) -> Result<(), Error> {
+        let alt = self.alt();
+        self.select_alt(OTP_ALT).await?;
+        let res = self.write_otp_area(address, buf).await;
+        self.select_alt(alt).await?;
+        res
+    }
+
+    async fn write_otp_area(&mut self, address: u32, buf: &[u8]) -> Result<(), Error> {
+        let current = self.read_otp_area().await?;
+        let expected = current.apply(address, buf)?;
+        if expected == current {
+            log::info!("OTP already holds the data, nothing to write");
+            return Ok(());
+        }
+        // bytes already holding their value get no programming pulse, the
+        // others can be programmed one by one as there is no ECC
+        for (address, run) in current.changes(address, buf)? {
+            self.dfuse_write(address, run).await?;
+        }
+        let written = self.read_otp_area().await?;
+        if written.lock != expected.lock {
+            return Err(Error::Otp(format!(
+                "Lock bytes read back as {:02X?}, expected {:02X?}",
+                written.lock, expected.lock
+            )));
+        }
+        if written.data != expected.data {
+            return Err(Error::Otp("OTP data read back differs".into()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_otp_apply() {
+        use super::{OtpImage, OtpLayout};
+        use crate::memory_layout::MemoryLayout;
+        use std::str::FromStr;
+        let layout =
+            MemoryLayout::from_str("@OTP Memory /0x1FFF7800/01*512 e,01*016 e").unwrap();
+        let layout = OtpLayout::from_layout(&layout).unwrap();
+        assert_eq!(0x1FFF_7A00, layout.lock_start);
+        assert_eq!(32, layout.block_size());
+        let mut image = OtpImage {
+            layout,
+            data: vec![0xFF; 512],
+            lock: vec![0xFF; 16],
+        };
+        image.data[0] = 0x12;
+        image.lock[1] = 0x00;
+
+        // programmed bytes may be written with the value they hold
+        let after = image.apply(0x1FFF_7800, &[0x12, 0x34]).unwrap();
+        assert_eq!(&[0x12, 0x34], &after.data[0..2]);
+        assert!(image.apply(0x1FFF_7800, &[0x13]).is_err());
+        // block 1 is locked
+        assert!(image.apply(0x1FFF_7820, &[0x00]).is_err());
+        assert!(image.apply(0x1FFF_7820, &[0xFF]).is_ok());
+        let after = image.apply(0x1FFF_7A00, &[0x00]).unwrap();
+        assert!(after.is_locked(0));
+        assert!(image.apply(0x1FFF_7A10, &[0x00]).is_err());
+        assert!(image.apply(0x1FFF_7000, &[0x00]).is_err());
+
+        image.data[2] = 0x56;
+        let buf = [0x12, 0x34, 0x56, 0x78, 0x9A];
+        assert_eq!(
+            vec![(0x1FFF_7801, &buf[1..2]), (0x1FFF_7803, &buf[3..5])],
+            image.changes(0x1FFF_7800, &buf).unwrap()
+        );
+        assert!(image.changes(0x1FFF_7800, &buf[0..1]).unwrap().is_empty());
+
+        // an area at the top of the address space
+        let layout = MemoryLayout::from_str("@OTP Memory /0xFFFFFFE0/01*016 e,01*016 e").unwrap();
+        let image = OtpImage {
+            layout: OtpLayout::from_layout(&layout).unwrap(),
+            data: vec![0xFF; 16],
+            lock: vec![0xFF; 16],
+        };
+        assert!(image.apply(0xFFFF_FFFE, &[0x00; 4]).is_err());
+        assert!(image.changes(0xFFFF_FFFE, &[0x00; 4]).is_err());
+        let after = image.apply(0xFFFF_FFFE, &[0x00; 2]).unwrap();
+        assert_eq!(&[0x00, 0x00], &after.lock[14..16]);
+    }
+}
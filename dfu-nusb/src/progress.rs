@@ -0,0 +1,106 @@
+use crate::memory_layout::EraseSector;
+use std::fmt;
+
+/// Long running part of an operation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Erase,
+    Write,
+    Read,
+    Verify,
+    Manifest,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Phase::Erase => write!(f, "Erase"),
+            Phase::Write => write!(f, "Write"),
+            Phase::Read => write!(f, "Read"),
+            Phase::Verify => write!(f, "Verify"),
+            Phase::Manifest => write!(f, "Manifest"),
+        }
+    }
+}
+
+/// Event passed to the observer set with `Dfu::set_progress`.
+///
+/// A total of 0 means the size is not known up front, as for a plain DFU
+/// upload that runs until the device sends a short packet.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Progress {
+    /// `total` bytes from `address` on are about to be handled.
+    Start { phase: Phase, address: u32, total: u32 },
+    /// `done` of `total` bytes are handled, up to `address`.
+    Bytes {
+        phase: Phase,
+        address: u32,
+        done: u32,
+        total: u32,
+    },
+    /// One sector erased, along with `Bytes` for the erase phase.
+    SectorErased(EraseSector),
+    /// The phase finished.
+    End { phase: Phase },
+}
+
+/// Receives `Progress` events, implemented for any `FnMut(&Progress)`.
+pub trait ProgressObserver: Send {
+    fn progress(&mut self, event: &Progress);
+}
+
+impl<F: FnMut(&Progress) + Send> ProgressObserver for F {
+    fn progress(&mut self, event: &Progress) {
+        self(event)
+    }
+}
+
+/// Byte count of a phase, reporting to an optional observer.
+pub(crate) struct Tracker {
+    phase: Phase,
+    address: u32,
+    done: u32,
+    total: u32,
+}
+
+impl Tracker {
+    pub(crate) fn start(
+        observer: &mut Option<Box<dyn ProgressObserver>>,
+        phase: Phase,
+        address: u32,
+        total: u32,
+    ) -> Self {
+        if let Some(o) = observer {
+            o.progress(&Progress::Start { phase, address, total });
+        }
+        Self {
+            phase,
+            address,
+            done: 0,
+            total,
+        }
+    }
+
+    pub(crate) fn add(&mut self, observer: &mut Option<Box<dyn ProgressObserver>>, bytes: u32) {
+        self.done += bytes;
+        if let Some(o) = observer {
+            o.progress(&Progress::Bytes {
+                phase: self.phase,
+                address: self.address(),
+                done: self.done,
+                total: self.total,
+            });
+        }
+    }
+
+    /// Where the phase got to, everything before is done.
+    pub(crate) fn address(&self) -> u32 {
+        self.address + self.done
+    }
+
+    pub(crate) fn end(self, observer: &mut Option<Box<dyn ProgressObserver>>) {
+        if let Some(o) = observer {
+            o.progress(&Progress::End { phase: self.phase });
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use crate::core::DFU_DETACH;
+use crate::descriptor::DfuDescriptor;
+use crate::error::Error;
+use crate::transport::{find_dfu_interface, DfuTransport, NusbTransport, DFU_PROTOCOL_DFU, DFU_PROTOCOL_RUNTIME};
+use crate::timer::{self, Instant};
+use std::io;
+use std::time::Duration;
+
+/// Time allowed for the device to show up in DFU mode after detach, on top
+/// of wDetachTimeout.
+const REENUMERATE_TIMEOUT: Duration = Duration::from_secs(5);
+const REENUMERATE_POLL: Duration = Duration::from_millis(100);
+/// Default time allowed for a device that reset itself to come back, long
+/// enough for the mass erase before a Read Unprotect reset on large parts.
+pub const RECONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Send DFU_DETACH to a device running its application.
+///
+/// wValue is the device's own wDetachTimeout. If the device does not set
+/// bitWillDetach it is reset right away so it comes back in DFU mode.
+/// Returns the runtime functional descriptor.
+pub async fn detach<T: DfuTransport>(transport: &mut T) -> Result<DfuDescriptor, Error> {
+    let desc = transport.functional_descriptor().ok_or_else(|| {
+        Error::DeviceNotFound("Missing runtime dfu functional descriptor".to_string())
+    })?;
+    let desc = DfuDescriptor::new(&desc)?;
+    let timeout = desc.detach_timeout.as_millis().min(u16::MAX as u128) as u16;
+    log::debug!("Detach with timeout {} ms will detach {}", timeout, desc.will_detach);
+    if let Err(e) = transport.control_out(DFU_DETACH, timeout, &[]).await {
+        // a device detaching on its own may drop off the bus before it acks
+        if !desc.will_detach {
+            return Err(Error::USB("Detach".into(), e));
+        }
+        log::debug!("Detach failed with {}, device is probably gone", e);
+    }
+    if !desc.will_detach {
+        transport.reset().map_err(|e| Error::USB("Reset".into(), e))?;
+    }
+    Ok(desc)
+}
+
+impl NusbTransport {
+    /// Open the DFU interface of the device matching `vid:pid`, detaching it
+    /// from its application first if it is in runtime mode.
+    pub async fn detach_from_vid_pid(vid: u16, pid: u16) -> Result<Self, Error> {
+        let device = nusb::list_devices()?
+            .find(|dev| dev.vendor_id() == vid && dev.product_id() == pid)
+            .ok_or_else(|| Error::DeviceNotFound(format!("{:04X}:{:04X}", vid, pid)))?;
+        Self::detach_device(device).await
+    }
+
+    /// Like `detach_from_vid_pid`, for the device at `bus:dev_addr`.
+    pub async fn detach_from_bus_device(bus: u8, dev_addr: u8) -> Result<Self, Error> {
+        let device = nusb::list_devices()?
+            .find(|dev| dev.bus_number() == bus && dev.device_address() == dev_addr)
+            .ok_or_else(|| Error::DeviceNotFound(format!("{}:{}", bus, dev_addr)))?;
+        Self::detach_device(device).await
+    }
+
+    async fn detach_device(device: nusb::DeviceInfo) -> Result<Self, Error> {
+        if let Some(iface) = find_dfu_interface(&device, DFU_PROTOCOL_DFU) {
+            log::debug!("Device is already in DFU mode");
+            let usb = device.open().map_err(|e| Error::USB("open".into(), e))?;
+            let mut transport = Self::new(usb, iface)?;
+            transport.info = Some(device);
+            return Ok(transport);
+        }
+        let iface = find_dfu_interface(&device, DFU_PROTOCOL_RUNTIME).ok_or_else(|| {
+            Error::DeviceNotFound(format!(
+                "{:04X}:{:04X} has no DFU runtime interface",
+                device.vendor_id(),
+                device.product_id()
+            ))
+        })?;
+        let usb = device.open().map_err(|e| Error::USB("open".into(), e))?;
+        let mut runtime = Self::new(usb, iface)?;
+        let desc = detach(&mut runtime).await?;
+        drop(runtime);
+
+        let device = wait_for_dfu_mode(&device, desc.detach_timeout + REENUMERATE_TIMEOUT).await?;
+        let iface = find_dfu_interface(&device, DFU_PROTOCOL_DFU).unwrap_or(0);
+        let usb = device.open().map_err(|e| Error::USB("open".into(), e))?;
+        let mut transport = Self::new(usb, iface)?;
+        transport.info = Some(device);
+        Ok(transport)
+    }
+
+    /// Time `reconnect` waits for the device to come back after it reset
+    /// itself, `RECONNECT_TIMEOUT` unless set.
+    pub fn set_reconnect_timeout(&mut self, timeout: Duration) {
+        self.reconnect_timeout = timeout;
+    }
+
+    /// Find the device again after it reset itself, and claim the same
+    /// interface on it.
+    pub(crate) async fn reopen(&mut self) -> io::Result<()> {
+        let info = self.info.clone().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Unsupported, "device was not opened from a listing")
+        })?;
+        let iface = self.interface_number();
+        let device = wait_for_dfu_mode(&info, self.reconnect_timeout)
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::NotFound, e.to_string()))?;
+        let usb = device.open()?;
+        let mut transport = Self::new(usb, iface).map_err(|e| io::Error::other(e.to_string()))?;
+        transport.info = Some(device);
+        transport.reconnect_timeout = self.reconnect_timeout;
+        *self = transport;
+        Ok(())
+    }
+}
+
+/// Where the device is plugged in, which stays the same when it
+/// re-enumerates.
+#[cfg(target_os = "linux")]
+fn port(device: &nusb::DeviceInfo) -> Option<String> {
+    device.sysfs_path().file_name().map(|p| p.to_string_lossy().into_owned())
+}
+
+#[cfg(target_os = "macos")]
+fn port(device: &nusb::DeviceInfo) -> Option<String> {
+    Some(format!("{:08X}", device.location_id()))
+}
+
+#[cfg(target_os = "windows")]
+fn port(device: &nusb::DeviceInfo) -> Option<String> {
+    Some(format!("{}#{}", device.parent_instance_id().to_string_lossy(), device.port_number()))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+fn port(_: &nusb::DeviceInfo) -> Option<String> {
+    None
+}
+
+/// Whether `dev` can be `old` after a re-enumeration: same vendor id, serial
+/// number if `old` had one, and port, or product id where the port isn't
+/// known.
+fn same_device(old: &nusb::DeviceInfo, dev: &nusb::DeviceInfo) -> bool {
+    let place = match (port(old), port(dev)) {
+        (Some(old), Some(dev)) => old == dev,
+        _ => old.product_id() == dev.product_id(),
+    };
+    dev.vendor_id() == old.vendor_id()
+        && (old.serial_number().is_none() || dev.serial_number() == old.serial_number())
+        && place
+}
+
+/// Wait for `old` to leave the bus and re-enumerate with a DFU mode
+/// interface.
+///
+/// Until `old` is seen gone, only a device at a new bus address is taken,
+/// so the instance that is about to reset is never picked up again.
+async fn wait_for_dfu_mode(old: &nusb::DeviceInfo, timeout: Duration) -> Result<nusb::DeviceInfo, Error> {
+    let deadline = Instant::now() + timeout;
+    let at_old_address = |dev: &nusb::DeviceInfo| {
+        dev.bus_number() == old.bus_number() && dev.device_address() == old.device_address()
+    };
+    let mut gone = false;
+    loop {
+        let devices: Vec<_> = nusb::list_devices()?.collect();
+        if !gone && !devices.iter().any(at_old_address) {
+            log::debug!("Device left {}:{}", old.bus_number(), old.device_address());
+            gone = true;
+        }
+        let found = devices.into_iter().find(|dev| {
+            (gone || !at_old_address(dev))
+                && same_device(old, dev)
+                && find_dfu_interface(dev, DFU_PROTOCOL_DFU).is_some()
+        });
+        if let Some(device) = found {
+            log::debug!(
+                "Device re-enumerated as {:04X}:{:04X} at {}:{}",
+                device.vendor_id(),
+                device.product_id(),
+                device.bus_number(),
+                device.device_address()
+            );
+            return Ok(device);
+        }
+        if Instant::now() >= deadline {
+            return Err(Error::DeviceNotFound(format!(
+                "{:04X}:{:04X} did not come back in DFU mode",
+                old.vendor_id(),
+                old.product_id()
+            )));
+        }
+        timer::sleep(REENUMERATE_POLL).await;
+    }
+}
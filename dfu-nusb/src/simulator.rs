@@ -0,0 +1,638 @@
+use crate::core::{Protocol, DFU_ABORT, DFU_CLRSTATUS, DFU_DETACH, DFU_DNLOAD, DFU_GETSTATE, DFU_GET_STATUS, DFU_UPLOAD};
+use crate::descriptor::{
+    ATTR_CAN_DNLOAD, ATTR_CAN_UPLOAD, ATTR_MANIFESTATION_TOLERANT, ATTR_WILL_DETACH, DFUSE_VERSION,
+};
+use crate::error::Error;
+use crate::memory_layout::MemoryLayout;
+use crate::status::{State, StatusCode};
+use crate::transport::{DfuTransport, DFU_FUNCTIONAL_DESCRIPTOR};
+use std::collections::BTreeMap;
+use std::io;
+use std::str::FromStr;
+use nusb::transfer::TransferError;
+
+/// String descriptor index naming alt setting 0, the next alt settings
+/// follow.
+const ALT_STRING_INDEX: u8 = 1;
+/// String descriptor index reported as iString along with errors.
+const STATUS_STRING_INDEX: u8 = 0xF0;
+
+/// Download waiting for the next DFU_GETSTATUS to be carried out.
+#[derive(Debug)]
+enum Pending {
+    Command(Vec<u8>),
+    Write { block: u16, data: Vec<u8> },
+}
+
+/// In-memory DfuSe target implementing `DfuTransport`.
+///
+/// Follows the DFU 1.1 state machine with the ST DfuSe command set on block 0
+/// and data blocks addressed from the address pointer. Flash is backed by the
+/// pages of a `MemoryLayout`, erased to 0xFF, and can only be programmed
+/// where erased.
+///
+/// Created with `new_dfu` it is a plain DFU 1.1 target instead, holding one
+/// firmware image that each download replaces.
+pub struct DfuSimulator {
+    protocol: Protocol,
+    alts: Vec<String>,
+    alt: u8,
+    pages: MemoryLayout,
+    flash: BTreeMap<u32, Vec<u8>>,
+    transfer_size: u16,
+    poll_timeout: u32,
+    busy_polls: u32,
+    busy_left: u32,
+    state: State,
+    status: StatusCode,
+    status_string: Option<String>,
+    address: u32,
+    pending: Option<Pending>,
+    erased: Vec<u32>,
+    image: Vec<u8>,
+    capacity: usize,
+    manifestation_tolerant: bool,
+    can_upload: bool,
+    will_detach: bool,
+    manifested: bool,
+    detach_timeout: Option<u16>,
+    resets: usize,
+    read_protected: bool,
+    reset_pending: bool,
+}
+
+impl DfuSimulator {
+    /// Create a fully erased target from a DfuSe memory layout string such as
+    /// "@Internal Flash  /0x08000000/64*002Kg".
+    pub fn new(layout: &str, transfer_size: u16) -> Result<Self, Error> {
+        let pages = MemoryLayout::from_str(layout)?;
+        let flash = pages
+            .pages()
+            .iter()
+            .map(|p| (p.address, vec![0xFF; p.size as usize]))
+            .collect();
+        Ok(Self {
+            protocol: Protocol::Dfuse,
+            alts: vec![layout.into()],
+            alt: 0,
+            pages,
+            flash,
+            transfer_size,
+            poll_timeout: 0,
+            busy_polls: 0,
+            busy_left: 0,
+            state: State::DfuIdle,
+            status: StatusCode::Ok,
+            status_string: None,
+            address: 0,
+            pending: None,
+            erased: Vec::new(),
+            image: Vec::new(),
+            capacity: 0,
+            manifestation_tolerant: false,
+            can_upload: true,
+            will_detach: true,
+            manifested: false,
+            detach_timeout: None,
+            resets: 0,
+            read_protected: false,
+            reset_pending: false,
+        })
+    }
+
+    /// Create a plain DFU 1.1 target with room for a `capacity` byte image.
+    pub fn new_dfu(capacity: usize, transfer_size: u16) -> Self {
+        Self {
+            protocol: Protocol::Dfu,
+            alts: vec!["Firmware".into()],
+            alt: 0,
+            pages: MemoryLayout::default(),
+            flash: BTreeMap::new(),
+            transfer_size,
+            poll_timeout: 0,
+            busy_polls: 0,
+            busy_left: 0,
+            state: State::DfuIdle,
+            status: StatusCode::Ok,
+            status_string: None,
+            address: 0,
+            pending: None,
+            erased: Vec::new(),
+            image: Vec::new(),
+            capacity,
+            manifestation_tolerant: false,
+            can_upload: true,
+            will_detach: true,
+            manifested: false,
+            detach_timeout: None,
+            resets: 0,
+            read_protected: false,
+            reset_pending: false,
+        }
+    }
+
+    /// Add an alt setting with its own DfuSe memory layout, returning its
+    /// number. Its pages share the flash of the other alt settings.
+    pub fn add_alt(&mut self, layout: &str) -> Result<u8, Error> {
+        let pages = MemoryLayout::from_str(layout)?;
+        for p in pages.pages() {
+            self.flash
+                .entry(p.address)
+                .or_insert_with(|| vec![0xFF; p.size as usize]);
+        }
+        self.alts.push(layout.into());
+        Ok(self.alts.len() as u8 - 1)
+    }
+
+    /// Alt setting selected last.
+    pub fn alt(&self) -> u8 {
+        self.alt
+    }
+
+    /// Set bitManifestationTolerant, returning to dfuIDLE after
+    /// manifestation instead of waiting for a reset.
+    pub fn set_manifestation_tolerant(&mut self, tolerant: bool) {
+        self.manifestation_tolerant = tolerant;
+    }
+
+    /// Enable readout protection: uploads from erasable sectors stall until
+    /// the Read Unprotect command mass erases them.
+    pub fn set_read_protected(&mut self, read_protected: bool) {
+        self.read_protected = read_protected;
+    }
+
+    /// Describe errors with this string, reported through iString.
+    pub fn set_status_string(&mut self, string: &str) {
+        self.status_string = Some(string.to_string());
+    }
+
+    /// Force the DFU state, e.g. `State::AppIdle` for a device running its
+    /// application.
+    pub fn set_state(&mut self, state: State) {
+        self.state = state;
+    }
+
+    /// Set bitWillDetach. Without it the device waits in appDETACH for a USB
+    /// reset after DFU_DETACH.
+    pub fn set_will_detach(&mut self, will_detach: bool) {
+        self.will_detach = will_detach;
+    }
+
+    /// wValue of the last DFU_DETACH received.
+    pub fn detach_timeout(&self) -> Option<u16> {
+        self.detach_timeout
+    }
+
+    /// Number of USB resets seen.
+    pub fn resets(&self) -> usize {
+        self.resets
+    }
+
+    /// Clear bitCanUpload, stalling every upload request.
+    pub fn set_can_upload(&mut self, can_upload: bool) {
+        self.can_upload = can_upload;
+    }
+
+    /// Firmware image of a plain DFU target.
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    /// Replace the firmware image of a plain DFU target.
+    pub fn set_image(&mut self, image: &[u8]) {
+        self.image = image.to_vec();
+    }
+
+    /// bwPollTimeout in milliseconds reported by DFU_GETSTATUS.
+    pub fn set_poll_timeout(&mut self, ms: u32) {
+        self.poll_timeout = ms;
+    }
+
+    /// Number of extra DFU_GETSTATUS requests the device stays in dfuDNBUSY
+    /// and dfuMANIFEST for.
+    pub fn set_busy_polls(&mut self, polls: u32) {
+        self.busy_polls = polls;
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Page addresses erased so far, in order.
+    pub fn erased_pages(&self) -> &[u32] {
+        &self.erased
+    }
+
+    /// Read flash directly, `None` if any byte is outside the layout.
+    pub fn read(&self, address: u32, length: u32) -> Option<Vec<u8>> {
+        (address..address.checked_add(length)?)
+            .map(|a| self.byte(a).copied())
+            .collect()
+    }
+
+    /// Overwrite flash directly, bypassing the DFU protocol.
+    pub fn load(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
+        for (i, b) in data.iter().enumerate() {
+            let a = address + i as u32;
+            *self.byte_mut(a).ok_or(Error::Address(a))? = *b;
+        }
+        Ok(())
+    }
+
+    fn byte(&self, address: u32) -> Option<&u8> {
+        let (base, page) = self.flash.range(..=address).next_back()?;
+        page.get((address - base) as usize)
+    }
+
+    fn byte_mut(&mut self, address: u32) -> Option<&mut u8> {
+        let (base, page) = self.flash.range_mut(..=address).next_back()?;
+        page.get_mut((address - base) as usize)
+    }
+
+    fn block_address(&self, block: u16) -> Option<u32> {
+        (block as u32 - 2)
+            .checked_mul(self.transfer_size as u32)?
+            .checked_add(self.address)
+    }
+
+    fn stall(&mut self) -> io::Error {
+        self.state = State::DfuError;
+        self.status = StatusCode::StalledPacket;
+        TransferError::Stall.into()
+    }
+
+    fn execute(&mut self, pending: Pending) -> Result<(), StatusCode> {
+        match pending {
+            Pending::Command(cmd) => self.command(&cmd),
+            Pending::Write { block, data } => self.write(block, &data),
+        }
+    }
+
+    fn command(&mut self, cmd: &[u8]) -> Result<(), StatusCode> {
+        let address = match cmd.len() {
+            1 => None,
+            5 => Some(u32::from_le_bytes([cmd[1], cmd[2], cmd[3], cmd[4]])),
+            _ => return Err(StatusCode::Target),
+        };
+        match (cmd[0], address) {
+            (0x21, Some(address)) => {
+                self.pages.address(address).map_err(|_| StatusCode::Target)?;
+                self.address = address;
+            }
+            (0x41, Some(address)) => {
+                let page = self.pages.address(address).map_err(|_| StatusCode::Target)?;
+                self.flash.insert(page.address, vec![0xFF; page.size as usize]);
+                self.erased.push(page.address);
+            }
+            (0x41, None) => {
+                for (address, page) in self.flash.iter_mut() {
+                    page.fill(0xFF);
+                    self.erased.push(*address);
+                }
+            }
+            (0x92, None) => {
+                // option bytes and OTP survive, and the device resets once
+                // the erase is done
+                for address in self.erasable_pages() {
+                    if let Some(page) = self.flash.get_mut(&address) {
+                        page.fill(0xFF);
+                        self.erased.push(address);
+                    }
+                }
+                self.read_protected = false;
+                self.reset_pending = true;
+            }
+            _ => return Err(StatusCode::Target),
+        }
+        Ok(())
+    }
+
+    /// Erasable pages of every alt setting.
+    fn erasable_pages(&self) -> Vec<u32> {
+        self.alts
+            .iter()
+            .filter_map(|layout| MemoryLayout::from_str(layout).ok())
+            .flat_map(|layout| layout.pages().to_vec())
+            .filter(|p| p.permissions.erasable)
+            .map(|p| p.address)
+            .collect()
+    }
+
+    /// Reset on the device's own accord, the host sees it drop off the bus.
+    fn reset_itself(&mut self) -> io::Error {
+        self.reset_pending = false;
+        self.resets += 1;
+        self.pending = None;
+        self.state = State::DfuIdle;
+        self.status = StatusCode::Ok;
+        self.alt = 0;
+        if let Ok(pages) = MemoryLayout::from_str(&self.alts[0]) {
+            self.pages = pages;
+        }
+        io::Error::new(io::ErrorKind::BrokenPipe, "device reset")
+    }
+
+    fn write(&mut self, block: u16, data: &[u8]) -> Result<(), StatusCode> {
+        if self.protocol == Protocol::Dfu {
+            return self.write_image(block, data);
+        }
+        let address = self.block_address(block).ok_or(StatusCode::Address)?;
+        // check the whole block first so a failed write leaves flash untouched
+        for (i, b) in data.iter().enumerate() {
+            let address = address + i as u32;
+            // sectors that can't be erased, like option bytes, are
+            // programmed in place
+            let in_place = self
+                .pages
+                .address(address)
+                .map(|p| !p.permissions.erasable)
+                .unwrap_or(false);
+            match self.byte(address) {
+                None => return Err(StatusCode::Address),
+                Some(old) if !in_place && *old != 0xFF && old != b => return Err(StatusCode::Prog),
+                Some(_) => {}
+            }
+        }
+        for (i, b) in data.iter().enumerate() {
+            if let Some(old) = self.byte_mut(address + i as u32) {
+                *old = *b;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_image(&mut self, block: u16, data: &[u8]) -> Result<(), StatusCode> {
+        if block == 0 {
+            self.image.clear();
+        }
+        let offset = block as usize * self.transfer_size as usize;
+        if offset != self.image.len() || offset + data.len() > self.capacity {
+            return Err(StatusCode::Address);
+        }
+        self.image.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn get_status(&mut self) -> Vec<u8> {
+        match self.state {
+            State::DfuDownloadSync => {
+                if let Some(pending) = self.pending.take() {
+                    match self.execute(pending) {
+                        Ok(()) => {
+                            self.state = State::DfuDownloadBusy;
+                            self.busy_left = self.busy_polls;
+                        }
+                        Err(status) => {
+                            self.state = State::DfuError;
+                            self.status = status;
+                        }
+                    }
+                } else {
+                    self.state = State::DfuDownloadIdle;
+                }
+            }
+            State::DfuDownloadBusy | State::DfuManifest if self.busy_left > 0 => self.busy_left -= 1,
+            State::DfuDownloadBusy => self.state = State::DfuDownloadIdle,
+            State::DfuManifestSync => {
+                if self.manifested {
+                    self.state = State::DfuIdle;
+                } else {
+                    self.state = State::DfuManifest;
+                    self.busy_left = self.busy_polls;
+                }
+            }
+            State::DfuManifest => {
+                self.manifested = true;
+                if self.manifestation_tolerant {
+                    self.state = State::DfuManifestSync;
+                } else {
+                    self.state = State::DfuManifestWaitReset;
+                }
+            }
+            _ => {}
+        }
+        let t = self.poll_timeout.to_le_bytes();
+        let string_index = match (&self.status_string, self.status) {
+            (Some(_), StatusCode::Ok) | (None, _) => 0,
+            (Some(_), _) => STATUS_STRING_INDEX,
+        };
+        vec![u8::from(&self.status), t[0], t[1], t[2], u8::from(&self.state), string_index]
+    }
+
+    fn upload(&mut self, block: u16, length: u16) -> io::Result<Vec<u8>> {
+        if !self.can_upload || !matches!(self.state, State::DfuIdle | State::DfuUploadIdle) {
+            return Err(self.stall());
+        }
+        if self.protocol == Protocol::Dfu {
+            let offset = (block as usize * self.transfer_size as usize).min(self.image.len());
+            let end = (offset + length as usize).min(self.image.len());
+            let data = self.image[offset..end].to_vec();
+            // a short packet ends the upload
+            self.state = if data.len() < length as usize {
+                State::DfuIdle
+            } else {
+                State::DfuUploadIdle
+            };
+            return Ok(data);
+        }
+        let data = match block {
+            0 => {
+                let mut cmds = vec![0x00, 0x21, 0x41, 0x92];
+                cmds.truncate(length as usize);
+                cmds
+            }
+            1 => return Err(self.stall()),
+            _ => {
+                let protected = self.read_protected
+                    && self
+                        .block_address(block)
+                        .and_then(|address| self.pages.address(address).ok())
+                        .map(|p| p.permissions.erasable)
+                        .unwrap_or(false);
+                if protected {
+                    return Err(self.stall());
+                }
+                let data = self
+                    .block_address(block)
+                    .and_then(|address| self.read(address, length as u32));
+                match data {
+                    Some(data) => data,
+                    None => {
+                        let e = self.stall();
+                        self.status = StatusCode::Address;
+                        return Err(e);
+                    }
+                }
+            }
+        };
+        self.state = State::DfuUploadIdle;
+        Ok(data)
+    }
+
+    fn download(&mut self, block: u16, data: &[u8]) -> io::Result<()> {
+        if !matches!(self.state, State::DfuIdle | State::DfuDownloadIdle) {
+            return Err(self.stall());
+        }
+        if data.is_empty() {
+            if self.state != State::DfuDownloadIdle {
+                return Err(self.stall());
+            }
+            self.state = State::DfuManifestSync;
+            self.manifested = false;
+            return Ok(());
+        }
+        if data.len() > self.transfer_size as usize
+            || (self.protocol == Protocol::Dfuse && block == 1)
+        {
+            return Err(self.stall());
+        }
+        self.pending = Some(if self.protocol == Protocol::Dfuse && block == 0 {
+            Pending::Command(data.to_vec())
+        } else {
+            Pending::Write {
+                block,
+                data: data.to_vec(),
+            }
+        });
+        self.state = State::DfuDownloadSync;
+        Ok(())
+    }
+}
+
+impl DfuTransport for DfuSimulator {
+    fn interface_number(&self) -> u8 {
+        0
+    }
+
+    async fn control_in(&mut self, request: u8, value: u16, length: u16) -> io::Result<Vec<u8>> {
+        match request {
+            DFU_GET_STATUS if self.reset_pending => Err(self.reset_itself()),
+            DFU_GET_STATUS => Ok(self.get_status()),
+            DFU_GETSTATE => Ok(vec![u8::from(&self.state)]),
+            DFU_UPLOAD => self.upload(value, length),
+            _ => Err(self.stall()),
+        }
+    }
+
+    async fn control_out(&mut self, request: u8, value: u16, data: &[u8]) -> io::Result<()> {
+        match request {
+            DFU_DNLOAD => self.download(value, data),
+            DFU_CLRSTATUS => {
+                if self.state != State::DfuError {
+                    return Err(self.stall());
+                }
+                self.state = State::DfuIdle;
+                self.status = StatusCode::Ok;
+                Ok(())
+            }
+            // like the ST bootloader, abort is ignored rather than stalled in
+            // states it does not apply to
+            DFU_ABORT => {
+                if !matches!(
+                    self.state,
+                    State::AppIdle
+                        | State::AppDetach
+                        | State::DfuError
+                        | State::DfuManifest
+                        | State::DfuManifestWaitReset
+                ) {
+                    self.state = State::DfuIdle;
+                    self.pending = None;
+                }
+                Ok(())
+            }
+            DFU_DETACH => {
+                self.detach_timeout = Some(value);
+                if self.state == State::AppIdle {
+                    // a device that detaches itself comes back in DFU mode
+                    self.state = if self.will_detach {
+                        State::DfuIdle
+                    } else {
+                        State::AppDetach
+                    };
+                }
+                Ok(())
+            }
+            _ => Err(self.stall()),
+        }
+    }
+
+    fn set_alt_setting(&mut self, alt: u8) -> io::Result<()> {
+        let layout = self
+            .alts
+            .get(alt as usize)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no such alt setting"))?;
+        if self.protocol == Protocol::Dfuse {
+            self.pages = MemoryLayout::from_str(layout)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        }
+        self.alt = alt;
+        Ok(())
+    }
+
+    async fn get_string_descriptor(&mut self, index: u8) -> io::Result<String> {
+        let alt = index.wrapping_sub(ALT_STRING_INDEX) as usize;
+        match (index, &self.status_string) {
+            (STATUS_STRING_INDEX, Some(string)) => Ok(string.clone()),
+            _ if alt < self.alts.len() => Ok(self.alts[alt].clone()),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "no such string")),
+        }
+    }
+
+    fn alt_string_index(&self, alt: u8) -> Option<u8> {
+        ((alt as usize) < self.alts.len()).then_some(ALT_STRING_INDEX + alt)
+    }
+
+    fn alt_settings(&self) -> Vec<u8> {
+        (0..self.alts.len() as u8).collect()
+    }
+
+    fn functional_descriptor(&self) -> Option<Vec<u8>> {
+        let size = self.transfer_size.to_le_bytes();
+        // 255 ms detach timeout
+        let mut attributes = ATTR_CAN_DNLOAD;
+        if self.will_detach {
+            attributes |= ATTR_WILL_DETACH;
+        }
+        if self.can_upload {
+            attributes |= ATTR_CAN_UPLOAD;
+        }
+        if self.manifestation_tolerant {
+            attributes |= ATTR_MANIFESTATION_TOLERANT;
+        }
+        let version = match self.protocol {
+            Protocol::Dfuse => DFUSE_VERSION,
+            Protocol::Dfu => 0x0110,
+        }
+        .to_le_bytes();
+        Some(vec![
+            9,
+            DFU_FUNCTIONAL_DESCRIPTOR,
+            attributes,
+            0xFF,
+            0x00,
+            size[0],
+            size[1],
+            version[0],
+            version[1],
+        ])
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.resets += 1;
+        self.pending = None;
+        self.status = StatusCode::Ok;
+        // a detaching device comes back in DFU mode, otherwise the
+        // application starts
+        self.state = if self.state == State::AppDetach {
+            State::DfuIdle
+        } else {
+            State::AppIdle
+        };
+        Ok(())
+    }
+
+    async fn reconnect(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
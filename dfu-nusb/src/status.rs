@@ -1,8 +1,8 @@
 use crate::core::*;
 use crate::error::Error;
+use crate::transport::DfuTransport;
 use std::fmt;
-use nusb;
-use nusb::transfer::{ControlIn, ControlType, Recipient};
+use std::time::Duration;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum State {
@@ -79,16 +79,154 @@ impl fmt::Display for State {
         }
     }
 }
+/// bStatus of DFU_GETSTATUS (DFU 1.1 section 6.1.2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StatusCode {
+    #[default]
+    Ok,
+    Target,
+    File,
+    Write,
+    Erase,
+    CheckErased,
+    Prog,
+    Verify,
+    Address,
+    NotDone,
+    Firmware,
+    Vendor,
+    UsbReset,
+    PowerOnReset,
+    Unknown,
+    StalledPacket,
+    /// Value not defined by the specification.
+    Reserved(u8),
+}
+
+impl From<&StatusCode> for u8 {
+    fn from(code: &StatusCode) -> u8 {
+        use crate::status::StatusCode::*;
+        match code {
+            StatusCode::Ok => 0x00,
+            Target => 0x01,
+            File => 0x02,
+            Write => 0x03,
+            Erase => 0x04,
+            CheckErased => 0x05,
+            Prog => 0x06,
+            Verify => 0x07,
+            Address => 0x08,
+            NotDone => 0x09,
+            Firmware => 0x0A,
+            Vendor => 0x0B,
+            UsbReset => 0x0C,
+            PowerOnReset => 0x0D,
+            Unknown => 0x0E,
+            StalledPacket => 0x0F,
+            Reserved(code) => *code,
+        }
+    }
+}
+
+impl From<u8> for StatusCode {
+    fn from(code: u8) -> StatusCode {
+        use crate::status::StatusCode::*;
+        match code {
+            0x00 => StatusCode::Ok,
+            0x01 => Target,
+            0x02 => File,
+            0x03 => Write,
+            0x04 => Erase,
+            0x05 => CheckErased,
+            0x06 => Prog,
+            0x07 => Verify,
+            0x08 => Address,
+            0x09 => NotDone,
+            0x0A => Firmware,
+            0x0B => Vendor,
+            0x0C => UsbReset,
+            0x0D => PowerOnReset,
+            0x0E => Unknown,
+            0x0F => StalledPacket,
+            code => Reserved(code),
+        }
+    }
+}
+
+impl StatusCode {
+    /// Name used by the specification, e.g. "errADDRESS".
+    pub fn name(&self) -> &'static str {
+        use crate::status::StatusCode::*;
+        match self {
+            StatusCode::Ok => "OK",
+            Target => "errTARGET",
+            File => "errFILE",
+            Write => "errWRITE",
+            Erase => "errERASE",
+            CheckErased => "errCHECK_ERASED",
+            Prog => "errPROG",
+            Verify => "errVERIFY",
+            Address => "errADDRESS",
+            NotDone => "errNOTDONE",
+            Firmware => "errFIRMWARE",
+            Vendor => "errVENDOR",
+            UsbReset => "errUSBR",
+            PowerOnReset => "errPOR",
+            Unknown => "errUNKNOWN",
+            StalledPacket => "errSTALLEDPKT",
+            Reserved(_) => "reserved",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        use crate::status::StatusCode::*;
+        match self {
+            StatusCode::Ok => "No error",
+            Target => "File is not targeted for use by this device",
+            File => "File is for this device but fails some vendor-specific verification test",
+            Write => "Device is unable to write memory",
+            Erase => "Memory erase function failed",
+            CheckErased => "Memory erase check failed",
+            Prog => "Program memory function failed",
+            Verify => "Programmed memory failed verification",
+            Address => "Cannot program memory due to received address that is out of range",
+            NotDone => "Zero length download but the device does not think it has all of the data yet",
+            Firmware => "Device's firmware is corrupt, it cannot return to run-time operation",
+            Vendor => "Vendor-specific error",
+            UsbReset => "Device detected unexpected USB reset signaling",
+            PowerOnReset => "Device detected unexpected power on reset",
+            Unknown => "Something went wrong, but the device does not know what it was",
+            StalledPacket => "Device stalled an unexpected request",
+            Reserved(_) => "Status code not defined by DFU 1.1",
+        }
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StatusCode::Reserved(code) => write!(f, "0x{:02X} ({})", code, self.description()),
+            _ => write!(f, "{} ({})", self.name(), self.description()),
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Status {
-    pub status: u8,
+    pub status: StatusCode,
     pub poll_timeout: usize,
     pub state: u8,
     pub string_index: u8,
+    /// String descriptor iString points at, read when the device reports an
+    /// error.
+    pub string: Option<String>,
 }
 impl fmt::Display for Status {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let _ = writeln!(f, "Status: {}", self.status).is_ok();
+        if let Some(string) = &self.string {
+            let _ = writeln!(f, "Device message: {}", string).is_ok();
+        }
         let _ = writeln!(f, "poll_timeout: {}", self.poll_timeout).is_ok();
         let _ = writeln!(f, "State: {}", State::from(self.state)).is_ok();
         write!(f, "string_index: {}", self.string_index)
@@ -96,16 +234,15 @@ impl fmt::Display for Status {
 }
 
 impl Status {
-    pub async fn get(interface: &nusb::Interface) -> Result<Self, Error> {
+    /// bwPollTimeout, the time the device wants before the next DFU_GETSTATUS.
+    pub fn poll_duration(&self) -> Duration {
+        Duration::from_millis(self.poll_timeout as u64)
+    }
+
+    pub async fn get<T: DfuTransport>(transport: &mut T) -> Result<Self, Error> {
         let mut s = Self::default();
-        let data: Vec<u8> = interface.control_in(ControlIn {
-            control_type: ControlType::Class,
-            recipient: Recipient::Interface,
-            request: DFU_GET_STATUS,
-            value: 0,
-            index: 0,
-            length: 6,
-        }).await.into_result().map_err(|e| Error::USB("Control transfer: DFU_GET_STATUS".into(), e.into()))?;
+        let data: Vec<u8> = transport.control_in(DFU_GET_STATUS, 0, 6).await
+            .map_err(|e| Error::USB("Control transfer: DFU_GET_STATUS".into(), e))?;
 
         let mut data = data.iter();
         if data.len() != 6 {
@@ -114,13 +251,161 @@ impl Status {
                 data.len()
             )));
         }
-        s.status = *(data.next().unwrap_or(&0_u8));
-        s.poll_timeout = ((*(data.next().unwrap_or(&0_u8)) as usize) << 16) as usize;
+        s.status = StatusCode::from(*(data.next().unwrap_or(&0_u8)));
+        // bwPollTimeout is 24 bits little endian
+        s.poll_timeout = (*(data.next().unwrap_or(&0_u8))) as usize;
         s.poll_timeout |= ((*(data.next().unwrap_or(&0_u8)) as usize) << 8) as usize;
-        s.poll_timeout |= (*(data.next().unwrap_or(&0_u8))) as usize;
+        s.poll_timeout |= ((*(data.next().unwrap_or(&0_u8)) as usize) << 16) as usize;
         s.state = *(data.next().unwrap_or(&0_u8));
         s.string_index = *(data.next().unwrap_or(&0_u8));
 
+        if s.status != StatusCode::Ok && s.string_index != 0 {
+            s.string = transport
+                .get_string_descriptor(s.string_index)
+                .await
+                .map_err(|e| log::debug!("Get status string {} failed cause {}", s.string_index, e))
+                .ok();
+        }
         Ok(s)
     }
 }
+
+/// Limits applied to the bwPollTimeout reported by the device, and how long
+/// status polling may go on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PollLimits {
+    /// Shortest wait between two DFU_GETSTATUS requests.
+    pub min: Duration,
+    /// Longest wait between two DFU_GETSTATUS requests, even if the device
+    /// asks for more.
+    pub max: Option<Duration>,
+    /// Wait before retrying a DFU_GETSTATUS with a malformed answer.
+    pub retry: Duration,
+    /// Wait before retrying a DFU_GETSTATUS that failed with a broken pipe.
+    pub pipe_retry: Duration,
+    /// Longest time a busy device may take to reach the state waited for,
+    /// such as dfuDNLOAD-IDLE after a sector erase or a block write.
+    pub timeout: Duration,
+    /// Longest time a mass erase may take.
+    pub mass_erase_timeout: Duration,
+}
+
+impl Default for PollLimits {
+    fn default() -> Self {
+        Self {
+            min: Duration::from_millis(1),
+            max: None,
+            retry: Duration::from_millis(100),
+            pipe_retry: Duration::from_millis(3000),
+            timeout: Duration::from_secs(10),
+            mass_erase_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl PollLimits {
+    /// Time to wait after `status` before asking again.
+    pub fn delay(&self, status: &Status) -> Duration {
+        let delay = status.poll_duration().max(self.min);
+        match self.max {
+            Some(max) => delay.min(max),
+            None => delay,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transport::DfuTransport;
+    use crate::{Error, State, Status, StatusCode};
+    use futures_lite::future::block_on;
+    use std::io;
+
+    /// Answers every class request with the same bytes.
+    struct Reply(Vec<u8>);
+
+    impl DfuTransport for Reply {
+        fn interface_number(&self) -> u8 {
+            0
+        }
+        async fn control_in(&mut self, _: u8, _: u16, _: u16) -> io::Result<Vec<u8>> {
+            Ok(self.0.clone())
+        }
+        async fn control_out(&mut self, _: u8, _: u16, _: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+        fn set_alt_setting(&mut self, _: u8) -> io::Result<()> {
+            Ok(())
+        }
+        async fn get_string_descriptor(&mut self, index: u8) -> io::Result<String> {
+            Ok(format!("string {}", index))
+        }
+        fn alt_string_index(&self, _: u8) -> Option<u8> {
+            None
+        }
+        fn alt_settings(&self) -> Vec<u8> {
+            Vec::new()
+        }
+        fn functional_descriptor(&self) -> Option<Vec<u8>> {
+            None
+        }
+        fn reset(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+        async fn reconnect(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_status_get() {
+        let s = block_on(Status::get(&mut Reply(vec![0x00, 0, 0, 0, 0x05, 0x03]))).unwrap();
+        assert_eq!(StatusCode::Ok, s.status);
+        assert_eq!(State::DfuDownloadIdle, State::from(s.state));
+        assert_eq!(3, s.string_index);
+        // only errors come with a message
+        assert_eq!(None, s.string);
+
+        let s = block_on(Status::get(&mut Reply(vec![0x0A, 0, 0, 0, 0x0A, 0x00]))).unwrap();
+        assert_eq!(StatusCode::Firmware, s.status);
+        assert_eq!(State::DfuError, State::from(s.state));
+        assert_eq!(None, s.string);
+
+        let s = block_on(Status::get(&mut Reply(vec![0x08, 0, 0, 0, 0x0A, 0x04]))).unwrap();
+        assert_eq!(StatusCode::Address, s.status);
+        assert_eq!(Some("string 4".to_string()), s.string);
+        assert!(s.to_string().contains("errADDRESS"));
+        assert!(s.to_string().contains("string 4"));
+
+        assert_eq!(StatusCode::Reserved(0x42), StatusCode::from(0x42));
+        assert_eq!(0x0F, u8::from(&StatusCode::StalledPacket));
+
+        assert!(matches!(
+            block_on(Status::get(&mut Reply(vec![0x00, 0, 0]))),
+            Err(Error::InvalidControlResponse(_))
+        ));
+    }
+
+    #[test]
+    fn test_status_poll_timeout() {
+        use crate::status::PollLimits;
+        use std::time::Duration;
+        let s = block_on(Status::get(&mut Reply(vec![0x00, 0x10, 0x27, 0x00, 0x04, 0x00]))).unwrap();
+        assert_eq!(10000, s.poll_timeout);
+        let s = block_on(Status::get(&mut Reply(vec![0x00, 0x01, 0x00, 0x01, 0x04, 0x00]))).unwrap();
+        assert_eq!(0x01_0001, s.poll_timeout);
+
+        let limits = PollLimits {
+            min: Duration::from_millis(5),
+            max: Some(Duration::from_secs(2)),
+            ..PollLimits::default()
+        };
+        let status = |poll_timeout| Status {
+            poll_timeout,
+            ..Status::default()
+        };
+        assert_eq!(Duration::from_millis(5), limits.delay(&status(0)));
+        assert_eq!(Duration::from_millis(250), limits.delay(&status(250)));
+        assert_eq!(Duration::from_secs(2), limits.delay(&status(10000)));
+    }
+}
@@ -0,0 +1,28 @@
+//! Delays while polling the device, from tokio or async-io depending on the
+//! enabled feature. With both enabled tokio is used.
+
+#[cfg(any(feature = "tokio", feature = "async-io"))]
+use std::time::Duration;
+
+#[cfg(not(any(feature = "tokio", feature = "async-io")))]
+compile_error!("dfu-nusb needs the \"tokio\" or the \"async-io\" feature for its timers");
+
+#[cfg(feature = "tokio")]
+pub(crate) use tokio::time::Instant;
+
+#[cfg(all(feature = "async-io", not(feature = "tokio")))]
+pub(crate) use std::time::Instant;
+
+#[cfg(feature = "tokio")]
+pub(crate) async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await
+}
+
+#[cfg(all(feature = "async-io", not(feature = "tokio")))]
+pub(crate) async fn sleep(duration: Duration) {
+    async_io::Timer::after(duration).await;
+}
+
+pub(crate) async fn sleep_until(deadline: Instant) {
+    sleep(deadline.saturating_duration_since(Instant::now())).await
+}
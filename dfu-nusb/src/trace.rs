@@ -0,0 +1,514 @@
+use crate::error::Error;
+use crate::transport::{is_stall, DfuTransport};
+use nusb::transfer::TransferError;
+use serde::{Deserialize, Serialize};
+use std::cell::{Ref, RefCell};
+use std::io::{self, BufRead, Write};
+use std::time::Instant;
+
+/// Error outcome of a recorded request.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceError {
+    Stall,
+    Cancelled,
+    Disconnected,
+    Fault,
+    BrokenPipe,
+    TimedOut,
+    Other(String),
+}
+
+impl From<&io::Error> for TraceError {
+    fn from(e: &io::Error) -> Self {
+        if is_stall(e) {
+            return TraceError::Stall;
+        }
+        match e.get_ref().and_then(|e| e.downcast_ref::<TransferError>()) {
+            Some(TransferError::Cancelled) => return TraceError::Cancelled,
+            Some(TransferError::Disconnected) => return TraceError::Disconnected,
+            Some(TransferError::Fault) => return TraceError::Fault,
+            _ => {}
+        }
+        match e.kind() {
+            io::ErrorKind::BrokenPipe => TraceError::BrokenPipe,
+            io::ErrorKind::TimedOut => TraceError::TimedOut,
+            _ => TraceError::Other(e.to_string()),
+        }
+    }
+}
+
+impl From<&TraceError> for io::Error {
+    fn from(e: &TraceError) -> Self {
+        use TraceError::*;
+        match e {
+            Stall => TransferError::Stall.into(),
+            Cancelled => TransferError::Cancelled.into(),
+            Disconnected => TransferError::Disconnected.into(),
+            Fault => TransferError::Fault.into(),
+            BrokenPipe => io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"),
+            TimedOut => io::Error::new(io::ErrorKind::TimedOut, "timed out"),
+            Other(msg) => io::Error::other(msg.clone()),
+        }
+    }
+}
+
+/// One transport operation as seen by `Dfu`.
+///
+/// `at_us` is the start of the request relative to the start of the
+/// recording and `duration_us` how long the device took to answer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Event {
+    ControlIn {
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+        #[serde(with = "hex")]
+        data: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<TraceError>,
+        at_us: u64,
+        duration_us: u64,
+    },
+    ControlOut {
+        request: u8,
+        value: u16,
+        index: u16,
+        #[serde(with = "hex")]
+        data: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<TraceError>,
+        at_us: u64,
+        duration_us: u64,
+    },
+    SetAltSetting {
+        alt: u8,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<TraceError>,
+    },
+    StringDescriptor {
+        index: u8,
+        string: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<TraceError>,
+    },
+    AltStringIndex {
+        alt: u8,
+        string_index: Option<u8>,
+    },
+    AltSettings {
+        alts: Vec<u8>,
+    },
+    FunctionalDescriptor {
+        #[serde(with = "hex_option")]
+        descriptor: Option<Vec<u8>>,
+    },
+    Reset {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<TraceError>,
+    },
+    Reconnect {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<TraceError>,
+    },
+}
+
+impl Event {
+    /// Queries that don't change the device and may be answered out of order.
+    fn is_query(&self) -> bool {
+        matches!(
+            self,
+            Event::AltStringIndex { .. } | Event::AltSettings { .. } | Event::FunctionalDescriptor { .. }
+        )
+    }
+}
+
+mod hex {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&data.iter().map(|b| format!("{:02X}", b)).collect::<String>())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        from_str(&String::deserialize(d)?).map_err(serde::de::Error::custom)
+    }
+
+    pub fn from_str(s: &str) -> Result<Vec<u8>, String> {
+        if s.len() % 2 != 0 {
+            return Err(format!("odd length hex string '{}'", s));
+        }
+        (0..s.len())
+            .step_by(2)
+            .map(|i| {
+                s.get(i..i + 2)
+                    .and_then(|b| u8::from_str_radix(b, 16).ok())
+                    .ok_or_else(|| format!("invalid hex string '{}'", s))
+            })
+            .collect()
+    }
+}
+
+mod hex_option {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &Option<Vec<u8>>, s: S) -> Result<S::Ok, S::Error> {
+        match data {
+            Some(data) => super::hex::serialize(data, s),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<u8>>, D::Error> {
+        Option::<String>::deserialize(d)?
+            .map(|s| super::hex::from_str(&s).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+/// Read a trace written by `Recorder`, one JSON event per line.
+pub fn read_trace<R: BufRead>(reader: R) -> Result<Vec<Event>, Error> {
+    let mut events = Vec::new();
+    for (n, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        events.push(
+            serde_json::from_str(&line)
+                .map_err(|e| Error::Trace(format!("line {}: {}", n + 1, e)))?,
+        );
+    }
+    Ok(events)
+}
+
+/// `DfuTransport` wrapper writing every operation to `W` as it happens.
+///
+/// Each event is written as one line of JSON and flushed, so a trace is
+/// usable even if the process is killed mid-transfer.
+pub struct Recorder<T: DfuTransport, W: Write> {
+    inner: T,
+    out: RefCell<W>,
+    start: Instant,
+}
+
+impl<T: DfuTransport, W: Write> Recorder<T, W> {
+    pub fn new(inner: T, out: W) -> Self {
+        Self {
+            inner,
+            out: RefCell::new(out),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn writer(&self) -> Ref<'_, W> {
+        self.out.borrow()
+    }
+
+    fn record(&self, event: &Event) {
+        let mut out = self.out.borrow_mut();
+        let res = serde_json::to_writer(&mut *out, event)
+            .map_err(io::Error::from)
+            .and_then(|_| out.write_all(b"\n"))
+            .and_then(|_| out.flush());
+        if let Err(e) = res {
+            log::warn!("Writing trace failed with {}", e);
+        }
+    }
+}
+
+impl<T: DfuTransport, W: Write> DfuTransport for Recorder<T, W> {
+    fn interface_number(&self) -> u8 {
+        self.inner.interface_number()
+    }
+
+    async fn control_in(&mut self, request: u8, value: u16, length: u16) -> io::Result<Vec<u8>> {
+        let at = self.start.elapsed();
+        let res = self.inner.control_in(request, value, length).await;
+        let duration = self.start.elapsed() - at;
+        self.record(&Event::ControlIn {
+            request,
+            value,
+            index: self.inner.interface_number() as u16,
+            length,
+            data: res.as_ref().map(Vec::clone).unwrap_or_default(),
+            error: res.as_ref().err().map(TraceError::from),
+            at_us: at.as_micros() as u64,
+            duration_us: duration.as_micros() as u64,
+        });
+        res
+    }
+
+    async fn control_out(&mut self, request: u8, value: u16, data: &[u8]) -> io::Result<()> {
+        let at = self.start.elapsed();
+        let res = self.inner.control_out(request, value, data).await;
+        let duration = self.start.elapsed() - at;
+        self.record(&Event::ControlOut {
+            request,
+            value,
+            index: self.inner.interface_number() as u16,
+            data: data.to_vec(),
+            error: res.as_ref().err().map(TraceError::from),
+            at_us: at.as_micros() as u64,
+            duration_us: duration.as_micros() as u64,
+        });
+        res
+    }
+
+    fn set_alt_setting(&mut self, alt: u8) -> io::Result<()> {
+        let res = self.inner.set_alt_setting(alt);
+        self.record(&Event::SetAltSetting {
+            alt,
+            error: res.as_ref().err().map(TraceError::from),
+        });
+        res
+    }
+
+    async fn get_string_descriptor(&mut self, index: u8) -> io::Result<String> {
+        let res = self.inner.get_string_descriptor(index).await;
+        self.record(&Event::StringDescriptor {
+            index,
+            string: res.as_ref().map(String::clone).unwrap_or_default(),
+            error: res.as_ref().err().map(TraceError::from),
+        });
+        res
+    }
+
+    fn alt_string_index(&self, alt: u8) -> Option<u8> {
+        let string_index = self.inner.alt_string_index(alt);
+        self.record(&Event::AltStringIndex { alt, string_index });
+        string_index
+    }
+
+    fn alt_settings(&self) -> Vec<u8> {
+        let alts = self.inner.alt_settings();
+        self.record(&Event::AltSettings { alts: alts.clone() });
+        alts
+    }
+
+    fn functional_descriptor(&self) -> Option<Vec<u8>> {
+        let descriptor = self.inner.functional_descriptor();
+        self.record(&Event::FunctionalDescriptor {
+            descriptor: descriptor.clone(),
+        });
+        descriptor
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        let res = self.inner.reset();
+        self.record(&Event::Reset {
+            error: res.as_ref().err().map(TraceError::from),
+        });
+        res
+    }
+
+    async fn reconnect(&mut self) -> io::Result<()> {
+        let res = self.inner.reconnect().await;
+        self.record(&Event::Reconnect {
+            error: res.as_ref().err().map(TraceError::from),
+        });
+        res
+    }
+}
+
+/// `DfuTransport` answering from a recorded trace.
+///
+/// Requests must arrive in the recorded order with the recorded arguments
+/// and payloads; the first request that differs fails with
+/// `io::ErrorKind::InvalidData` describing where the replay diverged, and
+/// so does every request after it. Descriptor queries are answered from any
+/// matching event in the trace.
+pub struct Replay {
+    events: Vec<Event>,
+    next: usize,
+    diverged: Option<String>,
+}
+
+impl Replay {
+    pub fn new(events: Vec<Event>) -> Self {
+        Self {
+            events,
+            next: 0,
+            diverged: None,
+        }
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self, Error> {
+        Ok(Self::new(read_trace(reader)?))
+    }
+
+    /// Recorded requests not replayed yet.
+    pub fn remaining(&self) -> impl Iterator<Item = &Event> {
+        self.events[self.next..].iter().filter(|e| !e.is_query())
+    }
+
+    fn take(&mut self, what: String) -> io::Result<Event> {
+        if let Some(msg) = &self.diverged {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg.clone()));
+        }
+        while let Some(event) = self.events.get(self.next) {
+            self.next += 1;
+            if !event.is_query() {
+                return Ok(event.clone());
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("trace ended before {}", what),
+        ))
+    }
+
+    fn diverged(&mut self, expected: &Event, what: String) -> io::Error {
+        let msg = format!(
+            "trace diverged at event {}: recorded {:?}, got {}",
+            self.next - 1,
+            expected,
+            what
+        );
+        self.diverged = Some(msg.clone());
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    }
+}
+
+impl DfuTransport for Replay {
+    fn interface_number(&self) -> u8 {
+        self.events
+            .iter()
+            .find_map(|e| match e {
+                Event::ControlIn { index, .. } | Event::ControlOut { index, .. } => {
+                    Some(*index as u8)
+                }
+                _ => None,
+            })
+            .unwrap_or(0)
+    }
+
+    async fn control_in(&mut self, request: u8, value: u16, length: u16) -> io::Result<Vec<u8>> {
+        let what = format!(
+            "control in request {} value {} length {}",
+            request, value, length
+        );
+        let event = self.take(what.clone())?;
+        match &event {
+            Event::ControlIn {
+                request: r,
+                value: v,
+                length: l,
+                data,
+                error,
+                ..
+            } if (*r, *v, *l) == (request, value, length) => match error {
+                Some(e) => Err(e.into()),
+                None => Ok(data.clone()),
+            },
+            _ => Err(self.diverged(&event, what)),
+        }
+    }
+
+    async fn control_out(&mut self, request: u8, value: u16, data: &[u8]) -> io::Result<()> {
+        let what = format!(
+            "control out request {} value {} data {:02X?}",
+            request, value, data
+        );
+        let event = self.take(what.clone())?;
+        match &event {
+            Event::ControlOut {
+                request: r,
+                value: v,
+                data: d,
+                error,
+                ..
+            } if (*r, *v, d.as_slice()) == (request, value, data) => match error {
+                Some(e) => Err(e.into()),
+                None => Ok(()),
+            },
+            _ => Err(self.diverged(&event, what)),
+        }
+    }
+
+    fn set_alt_setting(&mut self, alt: u8) -> io::Result<()> {
+        let what = format!("set alt setting {}", alt);
+        let event = self.take(what.clone())?;
+        match &event {
+            Event::SetAltSetting { alt: a, error } if *a == alt => match error {
+                Some(e) => Err(e.into()),
+                None => Ok(()),
+            },
+            _ => Err(self.diverged(&event, what)),
+        }
+    }
+
+    async fn get_string_descriptor(&mut self, index: u8) -> io::Result<String> {
+        let what = format!("string descriptor {}", index);
+        let event = self.take(what.clone())?;
+        match &event {
+            Event::StringDescriptor {
+                index: i,
+                string,
+                error,
+            } if *i == index => match error {
+                Some(e) => Err(e.into()),
+                None => Ok(string.clone()),
+            },
+            _ => Err(self.diverged(&event, what)),
+        }
+    }
+
+    fn alt_string_index(&self, alt: u8) -> Option<u8> {
+        self.events.iter().find_map(|e| match e {
+            Event::AltStringIndex { alt: a, string_index } if *a == alt => *string_index,
+            _ => None,
+        })
+    }
+
+    fn alt_settings(&self) -> Vec<u8> {
+        self.events
+            .iter()
+            .find_map(|e| match e {
+                Event::AltSettings { alts } => Some(alts.clone()),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    fn functional_descriptor(&self) -> Option<Vec<u8>> {
+        self.events.iter().find_map(|e| match e {
+            Event::FunctionalDescriptor { descriptor } => descriptor.clone(),
+            _ => None,
+        })
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        let what = "reset".to_string();
+        let event = self.take(what.clone())?;
+        match &event {
+            Event::Reset { error } => match error {
+                Some(e) => Err(e.into()),
+                None => Ok(()),
+            },
+            _ => Err(self.diverged(&event, what)),
+        }
+    }
+
+    async fn reconnect(&mut self) -> io::Result<()> {
+        let what = "reconnect".to_string();
+        let event = self.take(what.clone())?;
+        match &event {
+            Event::Reconnect { error } => match error {
+                Some(e) => Err(e.into()),
+                None => Ok(()),
+            },
+            _ => Err(self.diverged(&event, what)),
+        }
+    }
+}
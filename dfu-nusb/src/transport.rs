@@ -0,0 +1,305 @@
+use crate::error::Error;
+use crate::runtime::RECONNECT_TIMEOUT;
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use nusb::descriptors::language_id::US_ENGLISH;
+use nusb::transfer::{ControlIn, ControlOut, ControlType, Recipient, TransferError};
+
+/// DFU functional descriptor type.
+pub(crate) const DFU_FUNCTIONAL_DESCRIPTOR: u8 = 0x21;
+/// Standard GET_DESCRIPTOR request and the string descriptor type.
+const USB_REQUEST_GET_DESCRIPTOR: u8 = 6;
+const USB_STRING_DESCRIPTOR: u8 = 3;
+
+/// Application specific interface class used by DFU.
+pub const DFU_INTERFACE_CLASS: u8 = 0xFE;
+pub const DFU_INTERFACE_SUBCLASS: u8 = 0x01;
+/// Interface protocol of a device running its application.
+pub const DFU_PROTOCOL_RUNTIME: u8 = 0x01;
+/// Interface protocol of a device in DFU mode.
+pub const DFU_PROTOCOL_DFU: u8 = 0x02;
+
+/// The USB operations `Dfu` needs from a device.
+///
+/// All control transfers are class requests addressed to the DFU interface,
+/// so implementations fill in `wIndex` with their own interface number.
+pub trait DfuTransport {
+    /// Interface number used as `wIndex` for class requests.
+    fn interface_number(&self) -> u8;
+
+    /// Class request from device to host.
+    fn control_in(
+        &mut self,
+        request: u8,
+        value: u16,
+        length: u16,
+    ) -> impl Future<Output = io::Result<Vec<u8>>>;
+
+    /// Class request from host to device.
+    fn control_out(
+        &mut self,
+        request: u8,
+        value: u16,
+        data: &[u8],
+    ) -> impl Future<Output = io::Result<()>>;
+
+    /// Select the alternate setting of the DFU interface.
+    fn set_alt_setting(&mut self, alt: u8) -> io::Result<()>;
+
+    /// Fetch a string descriptor in US English.
+    fn get_string_descriptor(&mut self, index: u8) -> impl Future<Output = io::Result<String>>;
+
+    /// String descriptor index naming the alternate setting, if any.
+    fn alt_string_index(&self, alt: u8) -> Option<u8>;
+
+    /// Alternate settings of the DFU interface.
+    fn alt_settings(&self) -> Vec<u8>;
+
+    /// Raw DFU functional descriptor of the interface, if any.
+    fn functional_descriptor(&self) -> Option<Vec<u8>>;
+
+    /// USB port reset of the device.
+    fn reset(&mut self) -> io::Result<()>;
+
+    /// Open the device again after it reset itself and re-enumerated, and
+    /// claim the same interface.
+    fn reconnect(&mut self) -> impl Future<Output = io::Result<()>>;
+}
+
+/// Borrowed transport, so the owner gets it back once the `Dfu` is closed.
+impl<T: DfuTransport> DfuTransport for &mut T {
+    fn interface_number(&self) -> u8 {
+        (**self).interface_number()
+    }
+
+    async fn control_in(&mut self, request: u8, value: u16, length: u16) -> io::Result<Vec<u8>> {
+        (**self).control_in(request, value, length).await
+    }
+
+    async fn control_out(&mut self, request: u8, value: u16, data: &[u8]) -> io::Result<()> {
+        (**self).control_out(request, value, data).await
+    }
+
+    fn set_alt_setting(&mut self, alt: u8) -> io::Result<()> {
+        (**self).set_alt_setting(alt)
+    }
+
+    async fn get_string_descriptor(&mut self, index: u8) -> io::Result<String> {
+        (**self).get_string_descriptor(index).await
+    }
+
+    fn alt_string_index(&self, alt: u8) -> Option<u8> {
+        (**self).alt_string_index(alt)
+    }
+
+    fn alt_settings(&self) -> Vec<u8> {
+        (**self).alt_settings()
+    }
+
+    fn functional_descriptor(&self) -> Option<Vec<u8>> {
+        (**self).functional_descriptor()
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        (**self).reset()
+    }
+
+    async fn reconnect(&mut self) -> io::Result<()> {
+        (**self).reconnect().await
+    }
+}
+
+/// Returns true if the device stalled the control pipe.
+pub fn is_stall(e: &io::Error) -> bool {
+    matches!(
+        e.get_ref().and_then(|e| e.downcast_ref::<TransferError>()),
+        Some(TransferError::Stall)
+    )
+}
+
+/// Text of a string descriptor, UTF-16LE after the length and type bytes.
+fn decode_string_descriptor(data: &[u8]) -> io::Result<String> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid string descriptor");
+    let length = *data.first().ok_or_else(invalid)? as usize;
+    if length < 2 || length > data.len() || length % 2 != 0 || data[1] != USB_STRING_DESCRIPTOR {
+        return Err(invalid());
+    }
+    let units = data[2..length].chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]]));
+    Ok(char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect())
+}
+
+/// `DfuTransport` backed by a claimed `nusb` interface.
+pub struct NusbTransport {
+    usb: nusb::Device,
+    interface: nusb::Interface,
+    /// Where the device was found, to find it again after a reset.
+    pub(crate) info: Option<nusb::DeviceInfo>,
+    pub(crate) reconnect_timeout: Duration,
+}
+
+impl NusbTransport {
+    pub fn new(usb: nusb::Device, iface_index: u8) -> Result<Self, Error> {
+        let interface = usb.claim_interface(iface_index).map_err(|e| {
+            log::error!("Claim interface failed with {}", e);
+            Error::USB("Claim interface failed".into(), e)
+        })?;
+        Ok(Self {
+            usb,
+            interface,
+            info: None,
+            reconnect_timeout: RECONNECT_TIMEOUT,
+        })
+    }
+
+    pub fn from_bus_device(bus: u8, dev_addr: u8, iface_index: u8) -> Result<Self, Error> {
+        let device = nusb::list_devices()?
+            .find(|dev| dev.bus_number() == bus && dev.device_address() == dev_addr)
+            .ok_or_else(|| Error::DeviceNotFound(format!("{}:{}", bus, dev_addr)))?;
+
+        let usb = device.open().map_err(|e| Error::USB("open".into(), e))?;
+        let mut transport = Self::new(usb, iface_index)?;
+        transport.info = Some(device);
+        Ok(transport)
+    }
+
+    pub fn from_vid_pid(vid: u16, pid: u16, iface_index: u8) -> Result<Self, Error> {
+        let device = nusb::list_devices()?
+            .find(|dev| dev.vendor_id() == vid && dev.product_id() == pid)
+            .ok_or_else(|| Error::DeviceNotFound(format!("{:04X}:{:04X}", vid, pid)))?;
+
+        let usb = device.open().map_err(|e| Error::USB("open".into(), e))?;
+        let mut transport = Self::new(usb, iface_index)?;
+        transport.info = Some(device);
+        Ok(transport)
+    }
+
+    pub fn usb(&mut self) -> &mut nusb::Device {
+        &mut self.usb
+    }
+}
+
+/// Number of the first DFU interface of `device` speaking `protocol`,
+/// `DFU_PROTOCOL_RUNTIME` or `DFU_PROTOCOL_DFU`.
+pub fn find_dfu_interface(device: &nusb::DeviceInfo, protocol: u8) -> Option<u8> {
+    device
+        .interfaces()
+        .find(|i| {
+            i.class() == DFU_INTERFACE_CLASS
+                && i.subclass() == DFU_INTERFACE_SUBCLASS
+                && i.protocol() == protocol
+        })
+        .map(|i| i.interface_number())
+}
+
+impl DfuTransport for NusbTransport {
+    fn interface_number(&self) -> u8 {
+        self.interface.interface_number()
+    }
+
+    async fn control_in(&mut self, request: u8, value: u16, length: u16) -> io::Result<Vec<u8>> {
+        Ok(self.interface.control_in(ControlIn {
+            control_type: ControlType::Class,
+            recipient: Recipient::Interface,
+            request,
+            value,
+            index: self.interface.interface_number() as u16,
+            length,
+        }).await.into_result()?)
+    }
+
+    async fn control_out(&mut self, request: u8, value: u16, data: &[u8]) -> io::Result<()> {
+        self.interface.control_out(ControlOut {
+            control_type: ControlType::Class,
+            recipient: Recipient::Interface,
+            request,
+            value,
+            index: self.interface.interface_number() as u16,
+            data,
+        }).await.into_result()?;
+        Ok(())
+    }
+
+    fn set_alt_setting(&mut self, alt: u8) -> io::Result<()> {
+        self.interface.set_alt_setting(alt)
+    }
+
+    async fn get_string_descriptor(&mut self, index: u8) -> io::Result<String> {
+        if index == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "string index 0 is the language table"));
+        }
+        // sent on the claimed interface, nusb::Device only has a blocking
+        // string request and no async control transfers on Windows
+        let data = self.interface.control_in(ControlIn {
+            control_type: ControlType::Standard,
+            recipient: Recipient::Device,
+            request: USB_REQUEST_GET_DESCRIPTOR,
+            value: (USB_STRING_DESCRIPTOR as u16) << 8 | index as u16,
+            index: US_ENGLISH,
+            length: 255,
+        }).await.into_result()?;
+        decode_string_descriptor(&data)
+    }
+
+    fn alt_string_index(&self, alt: u8) -> Option<u8> {
+        let iface_index = self.interface.interface_number();
+        self.usb.active_configuration().ok()?.interface_alt_settings().find(|s| {
+            s.interface_number() == iface_index && s.alternate_setting() == alt
+        })?.string_index()
+    }
+
+    fn alt_settings(&self) -> Vec<u8> {
+        let iface_index = self.interface.interface_number();
+        match self.usb.active_configuration() {
+            Ok(conf) => conf
+                .interface_alt_settings()
+                .filter(|s| s.interface_number() == iface_index)
+                .map(|s| s.alternate_setting())
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    fn functional_descriptor(&self) -> Option<Vec<u8>> {
+        // only look behind the DFU interface, composite devices carry other
+        // class descriptors of the same type, such as the HID descriptor
+        let iface_index = self.interface.interface_number();
+        let conf = self.usb.active_configuration().ok()?;
+        conf.interface_alt_settings()
+            .filter(|s| {
+                s.interface_number() == iface_index
+                    && s.class() == DFU_INTERFACE_CLASS
+                    && s.subclass() == DFU_INTERFACE_SUBCLASS
+            })
+            .find_map(|s| {
+                s.descriptors()
+                    .find(|desc| desc.descriptor_type() == DFU_FUNCTIONAL_DESCRIPTOR)
+                    .map(|desc| desc.to_vec())
+            })
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.usb.reset()
+    }
+
+    async fn reconnect(&mut self) -> io::Result<()> {
+        self.reopen().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_string_descriptor;
+
+    #[test]
+    fn test_decode_string_descriptor() {
+        let data = [10, 3, b'D', 0, b'F', 0, b'U', 0, 0x3A, 0xD8, 0xFF];
+        // the unpaired surrogate is replaced, bytes past bLength are ignored
+        assert_eq!("DFU\u{FFFD}", decode_string_descriptor(&data).unwrap());
+        assert_eq!("", decode_string_descriptor(&[2, 3]).unwrap());
+        assert!(decode_string_descriptor(&[]).is_err());
+        assert!(decode_string_descriptor(&[4, 3, b'D']).is_err());
+        assert!(decode_string_descriptor(&[3, 3, b'D']).is_err());
+        assert!(decode_string_descriptor(&[4, 2, b'D', 0]).is_err());
+    }
+}
@@ -0,0 +1,23 @@
+//! Runs only with `--no-default-features --features async-io`, without any
+//! tokio runtime around.
+#![cfg(all(feature = "async-io", not(feature = "tokio")))]
+
+mod common;
+
+use common::pattern;
+use dfu_nusb::{CloseMode, Dfu, DfuSimulator};
+use futures_lite::future::block_on;
+use std::io::Cursor;
+
+#[test]
+fn test_download_without_tokio() {
+    block_on(async {
+        let mut sim = DfuSimulator::new("@Internal Flash  /0x08000000/64*002Kg", 1024).unwrap();
+        sim.set_poll_timeout(2);
+        let mut dfu = Dfu::from_transport(sim, 0).await.unwrap();
+        let data = pattern(3000);
+        dfu.download_raw(&mut Cursor::new(&data), 0x0800_0000, None).await.unwrap();
+        dfu.verify(&mut Cursor::new(&data), 0x0800_0000, None).await.unwrap();
+        dfu.close(CloseMode::StayInDfu).await.unwrap();
+    });
+}
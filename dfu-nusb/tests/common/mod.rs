@@ -0,0 +1,41 @@
+#![allow(dead_code)]
+
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+pub fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+}
+
+/// Scratch file removed again on drop.
+pub struct Scratch(pub PathBuf, pub File);
+
+impl Scratch {
+    pub fn new(name: &str, data: &[u8]) -> Self {
+        let path = std::env::temp_dir().join(format!("dfu-nusb-{}-{}", std::process::id(), name));
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(data).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        Self(path, file)
+    }
+
+    pub fn contents(&mut self) -> Vec<u8> {
+        let mut v = Vec::new();
+        self.1.seek(SeekFrom::Start(0)).unwrap();
+        self.1.read_to_end(&mut v).unwrap();
+        v
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
@@ -0,0 +1,127 @@
+//! Needs the tokio timers, the paused test clock doesn't drive async-io.
+#![cfg(feature = "tokio")]
+
+mod common;
+
+use common::{pattern, Scratch};
+use dfu_nusb::fault::{Fault, FaultInjector, Trigger};
+use dfu_nusb::{Dfu, DfuSimulator, Error, State};
+use std::io::ErrorKind;
+
+const LAYOUT: &str = "@Internal Flash  /0x08000000/64*002Kg";
+const XFER: u16 = 1024;
+const DFU_DNLOAD: u8 = 1;
+const DFU_GET_STATUS: u8 = 3;
+const LEN: usize = 5000;
+
+/// DNLOAD index of the second data block in `download_raw` of `LEN` bytes:
+/// three page erases, then a set address and a data block per transfer.
+const SECOND_DATA_BLOCK: usize = 6;
+
+/// Download `LEN` bytes with `fault` injected at `trigger`.
+///
+/// Returns the download result and the blocks left programmed, erased or
+/// neither, after which the device is brought back to dfuIDLE.
+async fn download(trigger: Trigger, fault: Fault) -> (Result<(), Error>, Vec<Block>) {
+    let mut injector = FaultInjector::new(DfuSimulator::new(LAYOUT, XFER).unwrap());
+    injector.inject(trigger, fault.clone());
+    let mut dfu = Dfu::from_transport(injector, 0).await.unwrap();
+    let data = pattern(LEN);
+    let mut image = Scratch::new(&format!("fault-{:?}", fault), &data);
+    let res = dfu.download_raw(&mut image.1, 0x0800_0000, LEN as u32).await;
+    assert_eq!(1, dfu.transport().injected().len());
+
+    let flash = dfu.transport().inner().read(0x0800_0000, LEN as u32).unwrap();
+    let blocks = data
+        .chunks(XFER as usize)
+        .zip(flash.chunks(XFER as usize))
+        .map(|(want, got)| {
+            if want == got {
+                Block::Written
+            } else if got.iter().all(|b| *b == 0xFF) {
+                Block::Erased
+            } else {
+                Block::Corrupt
+            }
+        })
+        .collect();
+
+    dfu.abort_to_idle_clear_once().await.unwrap();
+    assert_eq!(&State::DfuIdle, dfu.transport().inner().state());
+    (res, blocks)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Block {
+    Written,
+    Erased,
+    Corrupt,
+}
+
+use Block::*;
+
+#[tokio::test(start_paused = true)]
+async fn test_stall_on_data_block() {
+    // the stall is swallowed by dfuse_download, which aborts to idle, so the
+    // failure only shows once dfuDNBUSY never arrives
+    let (res, blocks) = download(Trigger::Request(DFU_DNLOAD, SECOND_DATA_BLOCK), Fault::Stall).await;
+    assert!(matches!(res, Err(Error::InvalidState(_, State::DfuDownloadBusy))));
+    assert_eq!(vec![Written, Erased, Erased, Erased, Erased], blocks);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_broken_pipe_on_data_block() {
+    let (res, blocks) =
+        download(Trigger::Request(DFU_DNLOAD, SECOND_DATA_BLOCK), Fault::BrokenPipe).await;
+    match res {
+        Err(Error::USB(_, e)) => assert_eq!(ErrorKind::BrokenPipe, e.kind()),
+        r => panic!("expected broken pipe, got {:?}", r),
+    }
+    assert_eq!(vec![Written, Erased, Erased, Erased, Erased], blocks);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_timeout_on_data_block() {
+    // the device got the block but it is only programmed on the next
+    // DFU_GETSTATUS, which never comes before the abort
+    let (res, blocks) =
+        download(Trigger::Request(DFU_DNLOAD, SECOND_DATA_BLOCK), Fault::Timeout).await;
+    match res {
+        Err(Error::USB(_, e)) => assert_eq!(ErrorKind::TimedOut, e.kind()),
+        r => panic!("expected timeout, got {:?}", r),
+    }
+    assert_eq!(vec![Written, Erased, Erased, Erased, Erased], blocks);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_status_faults_mid_transfer_are_retried() {
+    // DFU_GETSTATUS 14, counting from 0, is the first status read after the
+    // set address command of the second data block
+    for fault in [
+        Fault::Stall,
+        Fault::BrokenPipe,
+        Fault::Timeout,
+        Fault::ShortStatus(3),
+        Fault::DfuError(0x06),
+    ] {
+        let (res, blocks) = download(Trigger::Request(DFU_GET_STATUS, 14), fault.clone()).await;
+        assert!(res.is_ok(), "{:?} gave {:?}", fault, res);
+        assert_eq!(vec![Written; 5], blocks, "{:?}", fault);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_short_status_after_last_block() {
+    // abort_to_idle does not retry, so a bad final status fails the download
+    // even though every block is programmed
+    let injector = FaultInjector::new(DfuSimulator::new(LAYOUT, XFER).unwrap());
+    let mut dfu = Dfu::from_transport(injector, 0).await.unwrap();
+    let mut image = Scratch::new("fault-clean", &pattern(LEN));
+    dfu.download_raw(&mut image.1, 0x0800_0000, LEN as u32).await.unwrap();
+    let last = dfu.transport().transfers() - 1;
+    drop(dfu);
+
+    let (res, blocks) = download(Trigger::Transfer(last), Fault::ShortStatus(0)).await;
+    assert!(matches!(res, Err(Error::InvalidControlResponse(_))));
+    assert_eq!(vec![Written; 5], blocks);
+}
@@ -0,0 +1,730 @@
+//! Needs the tokio timers, the paused test clock doesn't drive async-io.
+#![cfg(feature = "tokio")]
+
+mod common;
+
+use common::{pattern, Scratch};
+use dfu_nusb::fault::FaultInjector;
+use dfu_nusb::memory_layout::Access;
+use dfu_nusb::option_bytes::{BorLevel, RdpLevel};
+use dfu_nusb::progress::{Phase, Progress};
+use dfu_nusb::runtime;
+use dfu_nusb::status::PollLimits;
+use dfu_nusb::{CloseMode, Dfu, DfuDevice, DfuSimulator, DfuseCommand, Manifestation, Protocol, State, StatusCode};
+use std::io::{Cursor, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+const LAYOUT: &str = "@Internal Flash  /0x08000000/64*002Kg";
+const XFER: u16 = 1024;
+
+async fn open(sim: DfuSimulator) -> Dfu<DfuSimulator> {
+    Dfu::from_transport(sim, 0).await.unwrap()
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_download_verify_upload() {
+    let mut dfu = open(DfuSimulator::new(LAYOUT, XFER).unwrap()).await;
+    // not a multiple of the transfer size and spanning three pages
+    let data = pattern(5000);
+    let mut image = Scratch::new("download", &data);
+    dfu.download_raw(&mut image.1, 0x0800_0800, data.len() as u32).await.unwrap();
+
+    let sim = dfu.transport();
+    assert_eq!(&State::DfuIdle, sim.state());
+    assert_eq!(&[0x0800_0800, 0x0800_1000, 0x0800_1800], sim.erased_pages());
+    assert_eq!(Some(data.clone()), sim.read(0x0800_0800, data.len() as u32));
+    assert_eq!(Some(vec![0xFF; 0x800]), sim.read(0x0800_0000, 0x800));
+    assert_eq!(Some(vec![0xFF; 8]), sim.read(0x0800_0800 + data.len() as u32, 8));
+
+    image.1.seek(SeekFrom::Start(0)).unwrap();
+    dfu.verify(&mut image.1, 0x0800_0800, data.len() as u32).await.unwrap();
+
+    let mut out = Scratch::new("upload", &[]);
+    dfu.upload(&mut out.1, 0x0800_0800, data.len() as u32).await.unwrap();
+    assert_eq!(data, out.contents());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_write_flash_from_slice() {
+    let mut sim = DfuSimulator::new(LAYOUT, XFER).unwrap();
+    sim.load(0x0800_0000, &[0x55; 3]).unwrap();
+    sim.load(0x0800_0814, &[0x66; 0x7EC]).unwrap();
+    let mut dfu = open(sim).await;
+    // misaligned start, several blocks and a short tail
+    let data = pattern(2 * XFER as usize + 17);
+    assert_eq!(data.len(), dfu.write_flash_from_slice(0x0800_0003, &data).await.unwrap());
+    let sim = dfu.transport();
+    assert_eq!(&State::DfuIdle, sim.state());
+    assert_eq!(&[0x0800_0000, 0x0800_0800], sim.erased_pages());
+    assert_eq!(Some(data.clone()), sim.read(0x0800_0003, data.len() as u32));
+    // the rest of the first and last sector is written back
+    assert_eq!(Some(vec![0x55; 3]), sim.read(0x0800_0000, 3));
+    assert_eq!(Some(vec![0x66; 0x7EC]), sim.read(0x0800_0814, 0x7EC));
+    assert_eq!(Some(vec![0xFF; 8]), sim.read(0x0800_1000, 8));
+
+    let mut buf = vec![0; data.len()];
+    assert_eq!(data.len(), dfu.read_flash_to_slice(0x0800_0003, &mut buf).await.unwrap());
+    assert_eq!(data, buf);
+
+    // without upload the rest of a sector can't be kept, nothing is erased
+    let mut sim = DfuSimulator::new(LAYOUT, XFER).unwrap();
+    sim.set_can_upload(false);
+    let mut dfu = open(sim).await;
+    assert!(matches!(
+        dfu.write_flash_from_slice(0x0800_0003, &data).await,
+        Err(dfu_nusb::Error::Unsupported(_))
+    ));
+    assert!(dfu.transport().erased_pages().is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_progress() {
+    let mut dfu = open(DfuSimulator::new(LAYOUT, XFER).unwrap()).await;
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let seen = events.clone();
+    dfu.set_progress(move |e: &Progress| seen.lock().unwrap().push(e.clone()));
+    let data = pattern(3000);
+    dfu.download_raw(&mut Cursor::new(&data), 0x0800_0000, None).await.unwrap();
+    dfu.verify(&mut Cursor::new(&data), 0x0800_0000, None).await.unwrap();
+
+    let events = events.lock().unwrap();
+    let erased: Vec<u32> = events
+        .iter()
+        .filter_map(|e| match e {
+            Progress::SectorErased(s) => Some(s.address),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(vec![0x0800_0000, 0x0800_0800], erased);
+    for phase in [Phase::Erase, Phase::Write, Phase::Verify] {
+        let bytes: Vec<(u32, u32)> = events
+            .iter()
+            .filter_map(|e| match e {
+                Progress::Bytes { phase: p, done, total, .. } if *p == phase => Some((*done, *total)),
+                _ => None,
+            })
+            .collect();
+        let total = if phase == Phase::Erase { 0x1000 } else { 3000 };
+        assert_eq!(Some(&(total, total)), bytes.last(), "{}", phase);
+        assert!(events.contains(&Progress::End { phase }));
+    }
+    assert_eq!(
+        Some(&Progress::Start { phase: Phase::Write, address: 0x0800_0000, total: 3000 }),
+        events.iter().find(|e| matches!(e, Progress::Start { phase: Phase::Write, .. }))
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_cancel() {
+    let mut dfu = open(DfuSimulator::new(LAYOUT, XFER).unwrap()).await;
+    let cancel = dfu.cancel_token();
+    let token = cancel.clone();
+    // cancel once the second block is written
+    dfu.set_progress(move |e: &Progress| {
+        if let Progress::Bytes { phase: Phase::Write, done: 2048, .. } = e {
+            token.cancel();
+        }
+    });
+    let data = pattern(5000);
+    assert!(matches!(
+        dfu.download_raw(&mut Cursor::new(&data), 0x0800_0000, None).await,
+        Err(dfu_nusb::Error::Cancelled(0x0800_0800))
+    ));
+    let sim = dfu.transport();
+    assert_eq!(&State::DfuIdle, sim.state());
+    assert_eq!(Some(data[..2048].to_vec()), sim.read(0x0800_0000, 2048));
+    assert_eq!(Some(vec![0xFF; 8]), sim.read(0x0800_0800, 8));
+
+    // a cancelled token stops the next operation before it starts
+    let mut out = Vec::new();
+    assert!(matches!(
+        dfu.upload(&mut out, 0x0800_0000, 16).await,
+        Err(dfu_nusb::Error::Cancelled(0x0800_0000))
+    ));
+    cancel.reset();
+    dfu.upload(&mut out, 0x0800_0000, 16).await.unwrap();
+    assert_eq!(&data[..16], &out[..]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_in_memory_image() {
+    let mut dfu = open(DfuSimulator::new(LAYOUT, XFER).unwrap()).await;
+    let data = pattern(3000);
+    // no length, the whole reader is taken
+    dfu.download_raw(&mut Cursor::new(&data), 0x0800_0000, None).await.unwrap();
+    dfu.verify(&mut Cursor::new(&data), 0x0800_0000, None).await.unwrap();
+    dfu.verify(&mut Cursor::new(&data[..100]), 0x0800_0000, 100).await.unwrap();
+
+    let mut out = Vec::new();
+    dfu.upload(&mut out, 0x0800_0000, data.len() as u32).await.unwrap();
+    assert_eq!(data, out);
+    assert!(matches!(
+        dfu.download_raw(&mut Cursor::new(Vec::new()), 0x0800_0000, None).await,
+        Err(dfu_nusb::Error::Argument(_))
+    ));
+
+    // a reader shorter than the length fails before anything is erased
+    assert!(matches!(
+        dfu.download_raw(&mut Cursor::new(&data), 0x0800_0800, 4000).await,
+        Err(dfu_nusb::Error::Argument(_))
+    ));
+    assert_eq!(Some(data[2048..].to_vec()), dfu.transport().read(0x0800_0800, 952));
+
+    // readers that can't seek are streamed with an explicit length
+    let data = pattern(2500);
+    dfu.download_stream(&mut data.as_slice(), 0x0800_1000, 2500).await.unwrap();
+    dfu.verify_stream(&mut data.as_slice(), 0x0800_1000, 2500).await.unwrap();
+    assert!(dfu.download_stream(&mut &data[..100], 0x0800_1000, 2500).await.is_err());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_transaction_wrap() {
+    // with 8 byte transfers the block number runs out after 524272 bytes
+    let sim = DfuSimulator::new("@Internal Flash  /0x08000000/512*002Kg", 8).unwrap();
+    let mut dfu = open(sim).await;
+    let data = pattern(600_000);
+    let mut image = Scratch::new("wrap", &data);
+    dfu.download_raw(&mut image.1, 0x0800_0000, data.len() as u32).await.unwrap();
+    assert_eq!(Some(data.clone()), dfu.transport().read(0x0800_0000, data.len() as u32));
+
+    image.1.seek(SeekFrom::Start(0)).unwrap();
+    dfu.verify(&mut image.1, 0x0800_0000, data.len() as u32).await.unwrap();
+    let mut out = Scratch::new("wrap-upload", &[]);
+    dfu.upload(&mut out.1, 0x0800_0000, data.len() as u32).await.unwrap();
+    assert_eq!(data, out.contents());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_verify_mismatch() {
+    let mut sim = DfuSimulator::new(LAYOUT, XFER).unwrap();
+    let mut data = pattern(3000);
+    sim.load(0x0800_0000, &data).unwrap();
+    data[2500] ^= 0x01;
+    let mut dfu = open(sim).await;
+    let mut image = Scratch::new("mismatch", &data);
+    assert!(matches!(
+        dfu.verify(&mut image.1, 0x0800_0000, data.len() as u32).await,
+        Err(dfu_nusb::Error::Verify(0x0800_09C4))
+    ));
+    dfu.abort_to_idle().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_erase() {
+    let mut sim = DfuSimulator::new(LAYOUT, XFER).unwrap();
+    sim.load(0x0800_0000, &pattern(0x2000)).unwrap();
+    let mut dfu = open(sim).await;
+
+    dfu.erase_pages(0x0800_0800, 0x1000).await.unwrap();
+    dfu.abort_to_idle().await.unwrap();
+    let sim = dfu.transport();
+    assert_eq!(&[0x0800_0800, 0x0800_1000], sim.erased_pages());
+    assert_eq!(Some(pattern(0x800)), sim.read(0x0800_0000, 0x800));
+    assert_eq!(Some(vec![0xFF; 0x1000]), sim.read(0x0800_0800, 0x1000));
+
+    dfu.mass_erase().await.unwrap();
+    dfu.abort_to_idle().await.unwrap();
+    assert_eq!(Some(vec![0xFF; 0x2000]), dfu.transport().read(0x0800_0000, 0x2000));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_erase_mixed_sectors() {
+    let sim = DfuSimulator::new("@Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg", XFER).unwrap();
+    let mut dfu = open(sim).await;
+    // starts inside the last 16K sector and ends one byte into the first 128K one
+    let plan = dfu.erase_plan(0x0800_C100, 0x14000).unwrap();
+    let addresses: Vec<u32> = plan.iter().map(|s| s.address).collect();
+    assert_eq!(vec![0x0800_C000, 0x0801_0000, 0x0802_0000], addresses);
+    assert_eq!(0, dfu.transport().erased_pages().len());
+
+    dfu.erase(&plan).await.unwrap();
+    dfu.abort_to_idle().await.unwrap();
+    assert_eq!(addresses.as_slice(), dfu.transport().erased_pages());
+}
+
+const OPTION_BYTES: &str = "@Option Bytes  /0x1FFFC000/01*016 e";
+
+#[tokio::test(start_paused = true)]
+async fn test_alt_settings() {
+    let mut sim = DfuSimulator::new(LAYOUT, XFER).unwrap();
+    assert_eq!(1, sim.add_alt(OPTION_BYTES).unwrap());
+    assert_eq!(2, sim.add_alt("@Device Feature/0xFFFF0000/01*004 e").unwrap());
+
+    let device = DfuDevice::from_transport(&mut sim).await.unwrap();
+    let names: Vec<&str> = device.alt_settings.iter().map(|a| a.name.as_str()).collect();
+    assert_eq!(vec!["Internal Flash", "Option Bytes", "Device Feature"], names);
+    let option_bytes = device.find("option bytes").unwrap();
+    assert_eq!(1, option_bytes.alt);
+    assert_eq!(OPTION_BYTES, option_bytes.string);
+    assert_eq!(0x1FFF_C000, option_bytes.layout.as_ref().unwrap().pages()[0].address);
+    assert!(device.find("OTP Memory").is_none());
+
+    let dfu = Dfu::from_transport_alt_name(sim, "Option Bytes").await.unwrap();
+    assert_eq!(1, dfu.alt());
+    assert_eq!(1, dfu.transport().alt());
+    assert_eq!("Option Bytes", dfu.memory_layout().name());
+    assert_eq!(3, dfu.device().alt_settings.len());
+
+    let sim = DfuSimulator::new(LAYOUT, XFER).unwrap();
+    assert!(matches!(
+        Dfu::from_transport_alt_name(sim, "Option Bytes").await,
+        Err(dfu_nusb::Error::Argument(_))
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_select_alt() {
+    let mut sim = DfuSimulator::new(LAYOUT, XFER).unwrap();
+    sim.add_alt(OPTION_BYTES).unwrap();
+    let mut dfu = open(sim).await;
+    let data = pattern(1000);
+    let mut image = Scratch::new("select-alt", &data);
+    dfu.download_raw(&mut image.1, 0x0800_0000, data.len() as u32).await.unwrap();
+
+    dfu.select_alt("Option Bytes").await.unwrap();
+    assert_eq!(1, dfu.alt());
+    assert_eq!(1, dfu.transport().alt());
+    assert_eq!("Option Bytes", dfu.memory_layout().name());
+    let mut buf = vec![0; 16];
+    dfu.read_flash_to_slice(0x1FFF_C000, &mut buf).await.unwrap();
+    assert_eq!(vec![0xFF; 16], buf);
+
+    // unknown names leave the session on the current alt setting
+    assert!(matches!(dfu.select_alt("OTP Memory").await, Err(dfu_nusb::Error::Argument(_))));
+    assert!(matches!(dfu.select_alt(7).await, Err(dfu_nusb::Error::Argument(_))));
+    assert_eq!(1, dfu.transport().alt());
+
+    // switching from the middle of a download ends it first
+    dfu.set_address(0x1FFF_C000).await.unwrap();
+    assert_eq!(&State::DfuDownloadIdle, dfu.transport().state());
+    dfu.select_alt(0).await.unwrap();
+    assert_eq!(&State::DfuIdle, dfu.transport().state());
+    assert_eq!("Internal Flash", dfu.memory_layout().name());
+    let mut buf = vec![0; 1000];
+    dfu.read_flash_to_slice(0x0800_0000, &mut buf).await.unwrap();
+    assert_eq!(data, buf);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_option_bytes() {
+    let mut sim = DfuSimulator::new(LAYOUT, XFER).unwrap();
+    sim.add_alt(OPTION_BYTES).unwrap();
+    // STM32F4 defaults: BOR off, level 0, no write protection
+    let mut block = vec![0xFF; 16];
+    block[0] = 0xEC;
+    block[1] = 0xAA;
+    block[9] = 0x0F;
+    sim.load(0x1FFF_C000, &block).unwrap();
+    let mut dfu = open(sim).await;
+
+    let mut options = dfu.read_option_bytes().await.unwrap();
+    assert_eq!(0, dfu.alt());
+    assert_eq!(RdpLevel::Level0, options.rdp_level());
+    assert_eq!(Some(BorLevel::Off), options.bor_level);
+    options.set("bor", "1").unwrap();
+    options.set("wrp", "0x3").unwrap();
+    dfu.write_option_bytes(&options).await.unwrap();
+    assert_eq!(0, dfu.alt());
+
+    let written = dfu.read_option_bytes().await.unwrap();
+    assert_eq!(Some(BorLevel::Level1), written.bor_level);
+    assert_eq!(0x3, written.wrp);
+    assert_eq!(Some(vec![0xE8, 0xAA]), dfu.transport().read(0x1FFF_C000, 2));
+    assert_eq!(Some(vec![0xFC, 0x0F]), dfu.transport().read(0x1FFF_C008, 2));
+
+    // stale options are refused
+    assert!(matches!(
+        dfu.write_option_bytes(&options).await,
+        Err(dfu_nusb::Error::OptionBytes(_))
+    ));
+    assert_eq!(0, dfu.alt());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_get_commands() {
+    let mut dfu = open(DfuSimulator::new(LAYOUT, XFER).unwrap()).await;
+    let cmds: Vec<String> = dfu
+        .dfuse_get_commands()
+        .await
+        .unwrap()
+        .iter()
+        .map(DfuseCommand::to_string)
+        .collect();
+    assert_eq!(vec!["Set address", "Page/Mass erase", "Read unprotected"], cmds);
+    dfu.abort_to_idle().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_read_outside_layout() {
+    let mut dfu = open(DfuSimulator::new(LAYOUT, XFER).unwrap()).await;
+    let mut buf = vec![0; 16];
+    assert!(dfu.read_flash_to_slice(0x0802_0000, &mut buf).await.is_err());
+    assert_eq!(&State::DfuError, dfu.transport().state());
+    dfu.abort_to_idle_clear_once().await.unwrap();
+    assert_eq!(&State::DfuIdle, dfu.transport().state());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_status_string() {
+    let mut sim = DfuSimulator::new(LAYOUT, XFER).unwrap();
+    sim.set_status_string("address not in flash");
+    let mut dfu = open(sim).await;
+    match dfu.set_address(0x0900_0000).await {
+        Err(dfu_nusb::Error::InvalidState(s, _)) => {
+            assert_eq!(StatusCode::Target, s.status);
+            assert_eq!(Some("address not in flash"), s.string.as_deref());
+        }
+        res => panic!("unexpected {:?}", res),
+    }
+    dfu.abort_to_idle_clear_once().await.unwrap();
+    let s = dfu.get_status(0).await.unwrap();
+    assert_eq!(StatusCode::Ok, s.status);
+    assert_eq!(None, s.string);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_dfu_download_upload() {
+    let mut sim = DfuSimulator::new_dfu(0x10000, XFER);
+    sim.set_manifestation_tolerant(true);
+    let mut dfu = open(sim).await;
+    assert_eq!(Protocol::Dfu, dfu.protocol());
+    let data = pattern(5000);
+    let mut image = Scratch::new("dfu-download", &data);
+    dfu.download_raw(&mut image.1, 0, data.len() as u32).await.unwrap();
+    assert_eq!(&State::DfuIdle, dfu.transport().state());
+    assert_eq!(data.as_slice(), dfu.transport().image());
+
+    image.1.seek(SeekFrom::Start(0)).unwrap();
+    dfu.verify(&mut image.1, 0, data.len() as u32).await.unwrap();
+
+    // length 0 reads until the short packet
+    let mut out = Scratch::new("dfu-upload", &[]);
+    dfu.upload(&mut out.1, 0, 0).await.unwrap();
+    assert_eq!(data, out.contents());
+    assert_eq!(&State::DfuIdle, dfu.transport().state());
+
+    let mut out = Scratch::new("dfu-upload-part", &[]);
+    dfu.upload(&mut out.1, 0, 2048).await.unwrap();
+    assert_eq!(&data[..2048], out.contents().as_slice());
+    assert_eq!(&State::DfuIdle, dfu.transport().state());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_dfu_manifest_wait_reset() {
+    let mut dfu = open(DfuSimulator::new_dfu(0x10000, XFER)).await;
+    let data = pattern(2048);
+    let mut image = Scratch::new("dfu-wait-reset", &data);
+    dfu.download_raw(&mut image.1, 0, data.len() as u32).await.unwrap();
+    // not manifestation tolerant, so the host resets it into its application
+    assert_eq!(1, dfu.transport().resets());
+    assert_eq!(&State::AppIdle, dfu.transport().state());
+    assert_eq!(data.as_slice(), dfu.transport().image());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_manifest() {
+    let mut sim = DfuSimulator::new(LAYOUT, XFER).unwrap();
+    sim.set_manifestation_tolerant(true);
+    let mut dfu = open(sim).await;
+    // manifestation needs a download in progress
+    assert!(matches!(dfu.manifest().await, Err(dfu_nusb::Error::USB(_, _))));
+    dfu.clear_status().await.unwrap();
+    dfu.set_address(0x0800_0000).await.unwrap();
+    assert_eq!(Manifestation::StayedInDfu, dfu.manifest().await.unwrap());
+    assert_eq!(&State::DfuIdle, dfu.transport().state());
+    assert_eq!(0, dfu.transport().resets());
+
+    let mut dfu = open(DfuSimulator::new(LAYOUT, XFER).unwrap()).await;
+    dfu.reset_stm32(0x0800_0000).await.unwrap();
+    assert_eq!(1, dfu.transport().resets());
+    assert_eq!(&State::AppIdle, dfu.transport().state());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_dfu_upload_block_multiple() {
+    let mut sim = DfuSimulator::new_dfu(0x10000, XFER);
+    // a multiple of the transfer size, so the upload ends on an empty packet
+    let data = pattern(2048);
+    sim.set_image(&data);
+    let mut dfu = open(sim).await;
+    let mut out = Scratch::new("dfu-upload-multiple", &[]);
+    dfu.upload(&mut out.1, 0, 0).await.unwrap();
+    assert_eq!(data, out.contents());
+    assert!(matches!(dfu.mass_erase().await, Err(dfu_nusb::Error::Unsupported(_))));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_poll_timeout() {
+    let mut sim = DfuSimulator::new(LAYOUT, XFER).unwrap();
+    sim.set_poll_timeout(500);
+    let mut dfu = open(sim).await;
+
+    // one status to check for dfuIDLE, then dfuDNBUSY and dfuDNLOAD-IDLE for
+    // each of the three pages, each waiting the 500 ms asked for
+    let start = Instant::now();
+    dfu.erase_pages(0x0800_0000, 0x1800).await.unwrap();
+    dfu.abort_to_idle().await.unwrap();
+    assert_eq!(Duration::from_millis(8 * 500), start.elapsed());
+
+    dfu.set_poll_limits(PollLimits {
+        max: Some(Duration::from_millis(50)),
+        ..PollLimits::default()
+    });
+    let start = Instant::now();
+    dfu.erase_pages(0x0800_0000, 0x1800).await.unwrap();
+    dfu.abort_to_idle().await.unwrap();
+    // the first status still waits for the uncapped timeout of the abort
+    assert_eq!(Duration::from_millis(500 + 7 * 50), start.elapsed());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_busy_timeout() {
+    // busy for far more polls than a fixed retry count allows, with no
+    // bwPollTimeout to slow the polling down
+    let mut sim = DfuSimulator::new(LAYOUT, XFER).unwrap();
+    sim.set_busy_polls(1000);
+    let mut dfu = open(sim).await;
+    dfu.erase_pages(0x0800_0000, 0x800).await.unwrap();
+    dfu.abort_to_idle().await.unwrap();
+
+    dfu.transport_mut().set_busy_polls(u32::MAX);
+    let start = Instant::now();
+    let res = dfu.erase_pages(0x0800_0000, 0x800).await;
+    assert!(matches!(res, Err(dfu_nusb::Error::InvalidState(_, State::DfuDownloadIdle))));
+    let elapsed = start.elapsed();
+    let timeout = PollLimits::default().timeout;
+    assert!(elapsed >= timeout && elapsed < timeout + Duration::from_millis(10), "{:?}", elapsed);
+
+    let mut sim = DfuSimulator::new(LAYOUT, XFER).unwrap();
+    sim.set_busy_polls(u32::MAX);
+    let mut dfu = open(sim).await;
+    dfu.set_poll_limits(PollLimits {
+        timeout: Duration::from_secs(1),
+        ..PollLimits::default()
+    });
+    let start = Instant::now();
+    let res = dfu.erase_pages(0x0800_0000, 0x800).await;
+    assert!(matches!(res, Err(dfu_nusb::Error::InvalidState(_, State::DfuDownloadIdle))));
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_secs(1) && elapsed < Duration::from_millis(1010), "{:?}", elapsed);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_dfu_busy_timeout() {
+    let mut sim = DfuSimulator::new_dfu(0x10000, XFER);
+    sim.set_busy_polls(u32::MAX);
+    let mut dfu = open(sim).await;
+    let mut image = Scratch::new("dfu-busy", &pattern(2000));
+    let start = Instant::now();
+    let res = dfu.download_raw(&mut image.1, 0, 2000).await;
+    assert!(matches!(res, Err(dfu_nusb::Error::InvalidState(_, State::DfuDownloadIdle))));
+    let elapsed = start.elapsed();
+    let timeout = PollLimits::default().timeout;
+    assert!(elapsed >= timeout && elapsed < timeout + Duration::from_millis(10), "{:?}", elapsed);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_manifest_timeout() {
+    let mut sim = DfuSimulator::new(LAYOUT, XFER).unwrap();
+    sim.set_manifestation_tolerant(true);
+    let mut dfu = open(sim).await;
+    dfu.set_address(0x0800_0000).await.unwrap();
+    dfu.transport_mut().set_busy_polls(u32::MAX);
+    let start = Instant::now();
+    let res = dfu.manifest().await;
+    assert!(matches!(res, Err(dfu_nusb::Error::InvalidState(_, State::DfuIdle))));
+    let elapsed = start.elapsed();
+    let timeout = PollLimits::default().timeout;
+    assert!(elapsed >= timeout && elapsed < timeout + Duration::from_millis(10), "{:?}", elapsed);
+    assert_eq!(&State::DfuManifest, dfu.transport().state());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_download_only_refuses_upload() {
+    let mut sim = DfuSimulator::new(LAYOUT, XFER).unwrap();
+    sim.set_can_upload(false);
+    let mut dfu = Dfu::from_transport(FaultInjector::new(sim), 0).await.unwrap();
+    assert!(dfu.dfu_descriptor().can_download);
+    assert!(!dfu.dfu_descriptor().can_upload);
+
+    let transfers = dfu.transport().transfers();
+    let mut out = Scratch::new("download-only", &[]);
+    assert!(matches!(
+        dfu.upload(&mut out.1, 0x0800_0000, 1024).await,
+        Err(dfu_nusb::Error::Unsupported(_))
+    ));
+    assert!(matches!(
+        dfu.verify(&mut out.1, 0x0800_0000, 1024).await,
+        Err(dfu_nusb::Error::Unsupported(_))
+    ));
+    assert_eq!(transfers, dfu.transport().transfers());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_sector_permissions() {
+    // read only boot sector, write only key sector, then normal flash
+    let sim = DfuSimulator::new("@Internal Flash  /0x08000000/01*002Ka,01*002Kf,62*002Kg", XFER).unwrap();
+    let mut dfu = Dfu::from_transport(FaultInjector::new(sim), 0).await.unwrap();
+    let transfers = dfu.transport().transfers();
+
+    let data = pattern(3000);
+    let mut image = Scratch::new("permissions", &data);
+    let res = dfu.download_raw(&mut image.1, 0x0800_0000, data.len() as u32).await;
+    match res {
+        Err(dfu_nusb::Error::Permission(p, Access::Write)) => assert_eq!(0x0800_0000, p.address),
+        res => panic!("unexpected {:?}", res),
+    }
+    assert!(matches!(
+        dfu.erase_pages(0x0800_0000, 0x800).await,
+        Err(dfu_nusb::Error::Permission(_, Access::Erase))
+    ));
+    assert!(matches!(
+        dfu.write_flash_from_slice(0x0800_07F0, &[0; 16]).await,
+        Err(dfu_nusb::Error::Permission(_, Access::Write))
+    ));
+    let mut buf = vec![0; 16];
+    assert!(matches!(
+        dfu.read_flash_to_slice(0x0800_0FF8, &mut buf).await,
+        Err(dfu_nusb::Error::Permission(_, Access::Read))
+    ));
+    let mut out = Scratch::new("permissions-upload", &[]);
+    let err = dfu.upload(&mut out.1, 0x0800_0000, 0x1000).await.unwrap_err();
+    assert!(err.to_string().contains("0x08000800"), "{}", err);
+    assert!(err.to_string().contains("not readable"), "{}", err);
+    assert_eq!(transfers, dfu.transport().transfers());
+
+    dfu.download_raw(&mut image.1, 0x0800_1000, data.len() as u32).await.unwrap();
+    assert_eq!(Some(data), dfu.transport().inner().read(0x0800_1000, 3000));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_runtime_detach() {
+    let mut sim = DfuSimulator::new(LAYOUT, XFER).unwrap();
+    sim.set_state(State::AppIdle);
+    let desc = runtime::detach(&mut sim).await.unwrap();
+    assert!(desc.will_detach);
+    assert_eq!(Some(255), sim.detach_timeout());
+    assert_eq!(0, sim.resets());
+    assert_eq!(&State::DfuIdle, sim.state());
+
+    // without bitWillDetach the host has to reset the device
+    let mut sim = DfuSimulator::new(LAYOUT, XFER).unwrap();
+    sim.set_will_detach(false);
+    sim.set_state(State::AppIdle);
+    let desc = runtime::detach(&mut sim).await.unwrap();
+    assert!(!desc.will_detach);
+    assert_eq!(1, sim.resets());
+    assert_eq!(&State::DfuIdle, sim.state());
+
+    let mut dfu = open(sim).await;
+    let data = pattern(1000);
+    let mut image = Scratch::new("runtime", &data);
+    dfu.download_raw(&mut image.1, 0x0800_0000, data.len() as u32).await.unwrap();
+    assert_eq!(Some(data), dfu.transport().read(0x0800_0000, 1000));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_read_unprotect() {
+    let mut sim = DfuSimulator::new(LAYOUT, XFER).unwrap();
+    sim.add_alt(OPTION_BYTES).unwrap();
+    // level 1, the option bytes themselves stay readable
+    let mut block = vec![0xFF; 16];
+    block[0] = 0xEC;
+    block[1] = 0x00;
+    block[9] = 0x0F;
+    sim.load(0x1FFF_C000, &block).unwrap();
+    sim.load(0x0800_0000, &pattern(16)).unwrap();
+    sim.set_read_protected(true);
+    let mut dfu = open(sim).await;
+
+    let mut buf = vec![0; 16];
+    match dfu.read_flash_to_slice(0x0800_0000, &mut buf).await {
+        Err(dfu_nusb::Error::ReadProtected(reason)) => assert!(reason.contains("Level 1")),
+        r => panic!("expected read protection, got {:?}", r),
+    }
+    assert_eq!(0, dfu.alt());
+    assert_eq!(&State::DfuIdle, dfu.transport().state());
+
+    dfu.read_unprotect().await.unwrap();
+    assert_eq!(1, dfu.transport().resets());
+    assert_eq!(0, dfu.alt());
+    assert_eq!(&State::DfuIdle, dfu.transport().state());
+    // flash is mass erased, the option bytes are left alone
+    assert_eq!(16, dfu.read_flash_to_slice(0x0800_0000, &mut buf).await.unwrap());
+    assert_eq!(vec![0xFF; 16], buf);
+    assert_eq!(Some(block), dfu.transport().read(0x1FFF_C000, 16));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_otp() {
+    let mut sim = DfuSimulator::new(LAYOUT, XFER).unwrap();
+    sim.add_alt("@OTP Memory /0x1FFF7800/01*512 e,01*016 e").unwrap();
+    let mut dfu = open(sim).await;
+
+    let serial = pattern(40);
+    dfu.write_otp(0x1FFF_7800, &serial).await.unwrap();
+    dfu.write_otp(0x1FFF_7A01, &[0x00]).await.unwrap();
+    assert_eq!(0, dfu.alt());
+    let otp = dfu.read_otp().await.unwrap();
+    assert_eq!(&serial[..], &otp.data[0..40]);
+    assert!(!otp.is_locked(0));
+    assert!(otp.is_locked(1));
+
+    // rewriting the same bytes is harmless, changing programmed bytes or
+    // writing to the locked block is refused
+    dfu.write_otp(0x1FFF_7800, &serial[0..8]).await.unwrap();
+    for (address, data) in [(0x1FFF_7801, [0xAA]), (0x1FFF_7830, [0x00]), (0x1FFF_7A01, [0x01])] {
+        assert!(matches!(
+            dfu.write_otp(address, &data).await,
+            Err(dfu_nusb::Error::Otp(_))
+        ));
+    }
+    assert_eq!(otp, dfu.read_otp().await.unwrap());
+    assert_eq!(0, dfu.alt());
+    assert!(dfu.transport().erased_pages().is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_close() {
+    let mut sim = DfuSimulator::new(LAYOUT, XFER).unwrap();
+    let mut dfu = Dfu::from_transport(&mut sim, 0).await.unwrap();
+    dfu.set_address(0x0800_0000).await.unwrap();
+    dfu.close(CloseMode::StayInDfu).await.unwrap();
+    assert_eq!(&State::DfuIdle, sim.state());
+    assert_eq!(0, sim.resets());
+
+    let dfu = Dfu::from_transport(&mut sim, 0).await.unwrap();
+    dfu.close(CloseMode::Leave(0x0800_0000)).await.unwrap();
+    assert_eq!(1, sim.resets());
+    assert_eq!(&State::AppIdle, sim.state());
+
+    sim.set_state(State::DfuIdle);
+    let dfu = Dfu::from_transport(&mut sim, 0).await.unwrap();
+    dfu.close(CloseMode::Reset).await.unwrap();
+    assert_eq!(2, sim.resets());
+
+    // without bitWillDetach the detach is followed by a reset
+    sim.set_state(State::DfuIdle);
+    sim.set_will_detach(false);
+    let dfu = Dfu::from_transport(&mut sim, 0).await.unwrap();
+    dfu.close(CloseMode::Detach).await.unwrap();
+    assert_eq!(Some(255), sim.detach_timeout());
+    assert_eq!(3, sim.resets());
+
+    // a device detached earlier is left alone
+    sim.set_state(State::DfuIdle);
+    let mut dfu = Dfu::from_transport(&mut sim, 0).await.unwrap();
+    dfu.detach().await.unwrap();
+    dfu.close(CloseMode::StayInDfu).await.unwrap();
+    assert_eq!(3, sim.resets());
+
+    // errors reach the caller
+    sim.set_state(State::DfuIdle);
+    let mut injector = FaultInjector::new(&mut sim);
+    injector.inject(dfu_nusb::fault::Trigger::Request(6, 0), dfu_nusb::fault::Fault::BrokenPipe);
+    let mut dfu = Dfu::from_transport(injector, 0).await.unwrap();
+    dfu.set_address(0x0800_0000).await.unwrap();
+    assert!(matches!(dfu.close(CloseMode::StayInDfu).await, Err(dfu_nusb::Error::USB(_, _))));
+}
@@ -0,0 +1,73 @@
+//! Needs the tokio timers, the paused test clock doesn't drive async-io.
+#![cfg(feature = "tokio")]
+
+mod common;
+
+use common::{pattern, Scratch};
+use dfu_nusb::trace::{read_trace, Event};
+use dfu_nusb::{Dfu, DfuSimulator, Recorder, Replay};
+use std::io::{Seek, SeekFrom};
+
+const LAYOUT: &str = "@Internal Flash  /0x08000000/64*002Kg";
+const XFER: u16 = 1024;
+
+/// Run a download and verify against the simulator and return the trace.
+async fn record(data: &[u8]) -> Vec<u8> {
+    let recorder = Recorder::new(DfuSimulator::new(LAYOUT, XFER).unwrap(), Vec::new());
+    let mut dfu = Dfu::from_transport(recorder, 0).await.unwrap();
+    let mut image = Scratch::new("record", data);
+    dfu.download_raw(&mut image.1, 0x0800_0000, data.len() as u32).await.unwrap();
+    image.1.seek(SeekFrom::Start(0)).unwrap();
+    dfu.verify(&mut image.1, 0x0800_0000, data.len() as u32).await.unwrap();
+    let trace = dfu.transport().writer().clone();
+    trace
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_record_replay() {
+    let data = pattern(3000);
+    let trace = record(&data).await;
+    let events = read_trace(trace.as_slice()).unwrap();
+    assert!(events.iter().any(|e| matches!(e, Event::FunctionalDescriptor { descriptor: Some(_) })));
+    assert!(events.iter().any(|e| matches!(e, Event::ControlOut { request: 1, value: 4, data, .. } if data.len() == 952)));
+
+    let mut dfu = Dfu::from_transport(Replay::new(events), 0).await.unwrap();
+    let mut image = Scratch::new("replay", &data);
+    dfu.download_raw(&mut image.1, 0x0800_0000, data.len() as u32).await.unwrap();
+    image.1.seek(SeekFrom::Start(0)).unwrap();
+    dfu.verify(&mut image.1, 0x0800_0000, data.len() as u32).await.unwrap();
+    assert_eq!(0, dfu.transport().remaining().count());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_replay_diverged() {
+    let mut data = pattern(3000);
+    let trace = record(&data).await;
+    let mut dfu = Dfu::from_transport(Replay::from_reader(trace.as_slice()).unwrap(), 0)
+        .await
+        .unwrap();
+    data[1500] ^= 0xFF;
+    let mut image = Scratch::new("diverged", &data);
+    match dfu.download_raw(&mut image.1, 0x0800_0000, data.len() as u32).await {
+        Err(dfu_nusb::Error::USB(_, e)) => assert_eq!(std::io::ErrorKind::InvalidData, e.kind()),
+        r => panic!("expected divergence, got {:?}", r),
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_replay_error() {
+    let recorder = Recorder::new(DfuSimulator::new(LAYOUT, XFER).unwrap(), Vec::new());
+    let mut dfu = Dfu::from_transport(recorder, 0).await.unwrap();
+    let mut buf = vec![0; 16];
+    let recorded = dfu.read_flash_to_slice(0x0802_0000, &mut buf).await.unwrap_err();
+    dfu.abort_to_idle_clear_once().await.unwrap();
+    let trace = dfu.transport().writer().clone();
+
+    let mut dfu = Dfu::from_transport(Replay::from_reader(trace.as_slice()).unwrap(), 0)
+        .await
+        .unwrap();
+    let replayed = dfu.read_flash_to_slice(0x0802_0000, &mut buf).await.unwrap_err();
+    assert_eq!(recorded.to_string(), replayed.to_string());
+    dfu.abort_to_idle_clear_once().await.unwrap();
+    assert_eq!(0, dfu.transport().remaining().count());
+}